@@ -0,0 +1,6185 @@
+#[macro_use]
+extern crate bitflags;
+extern crate flate2;
+extern crate tar;
+extern crate xz2;
+extern crate zip;
+extern crate bzip2;
+extern crate zstd;
+extern crate lz4;
+extern crate rust_lzo;
+extern crate glob;
+extern crate sha2;
+extern crate sha1;
+extern crate md5;
+extern crate sevenz_rust;
+extern crate ar;
+extern crate encoding_rs;
+extern crate brotli;
+extern crate regex;
+extern crate memmap2;
+extern crate serde;
+#[cfg(feature = "rar")]
+extern crate unrar;
+#[cfg(any(feature = "pure-rust-xz", feature = "parallel-xz"))]
+extern crate lzma_rust2;
+#[cfg(feature = "pure-rust-bzip2")]
+extern crate bzip2_pure;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "async")]
+extern crate async_compression;
+
+use std::fmt;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf, Component};
+use std::io::{Read, BufRead, Write, Seek, SeekFrom, Cursor, Chain, BufReader, BufWriter, Error,
+              ErrorKind};
+use std::process::Command;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, FileExt, PermissionsExt};
+#[cfg(windows)]
+use std::os::windows::fs::FileExt;
+use std::sync::{mpsc, Mutex};
+use std::rc::Rc;
+use std::cell::Cell;
+use std::ops::ControlFlow;
+use std::thread;
+use zip::ZipArchive;
+use zip::read::ZipFile;
+use zip::result::ZipError;
+use encoding_rs::Encoding;
+use bzip2::read::MultiBzDecoder;
+use bzip2::write::BzEncoder;
+use xz2::read::XzDecoder;
+use xz2::write::XzEncoder;
+use xz2::stream::{Stream, Action, Status};
+use flate2::{Crc, GzHeader};
+use rust_lzo::{LZOContext, LZOError};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+use zstd::stream::write::Encoder as ZstdEncoder;
+use lz4::Decoder as Lz4Decoder;
+use brotli::Decompressor as BrotliDecoder;
+use tar::{Builder, Header, EntryType};
+use glob::Pattern;
+use regex::{Regex, RegexBuilder};
+use memmap2::Mmap;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sha1::Sha1;
+use md5::Md5;
+use sevenz_rust::{SevenZReader, SevenZArchiveEntry, Password};
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "async")]
+use std::task::{Context, Poll};
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncBufRead, AsyncWrite, ReadBuf};
+#[cfg(feature = "async")]
+use async_compression::tokio::bufread::{GzipDecoder as AsyncGzipDecoder,
+                                        BzDecoder as AsyncBzDecoder,
+                                        XzDecoder as AsyncXzDecoder,
+                                        ZstdDecoder as AsyncZstdDecoder};
+
+bitflags! {
+    pub flags ArchiveType: u32 {
+        const INVALID  = 0b000000000,
+        const TAR      = 0b000000001,
+        const GZIP     = 0b000000010,
+        const ZIP      = 0b000000100,
+        const XZ       = 0b000001000, 
+        const BZIP2    = 0b000010000,
+        const ZSTD     = 0b000100000,
+        const LZ4      = 0b001000000,
+        const LZMA     = 0b010000000,
+        const COMPRESS = 0b100000000,
+        const RAR      = 0b1000000000,
+        const SEVENZIP = 0b10000000000,
+        const CPIO     = 0b100000000000,
+        const AR       = 0b1000000000000,
+        const BROTLI   = 0b10000000000000,
+        const LZIP     = 0b100000000000000,
+        const LZOP     = 0b1000000000000000,
+        // Not a format, asks for detection with the other flags as fallback
+        const AUTO     = 1 << 31,
+        const _ALL     = (0b1000000000000000 << 1) - 1
+    }
+}
+
+// Chunked trait for reading in chunks of size of the buffer. The callback
+// gets each chunk as read and breaks to stop early, the bytes read up to then
+// are returned. An error it returns stops the reading and is passed on, read
+// errors come out as E too
+pub trait Chunked {
+    fn chunked<F, E>(&mut self, buffer: &mut [u8], callback: F) -> Result<usize, E>
+        where F: FnMut(&[u8]) -> Result<ControlFlow<()>, E>,
+              E: From<Error>;
+}
+
+// Implement the Chunked trait for the Read trait
+impl<R: Read> Chunked for R {
+    fn chunked<F, E>(&mut self, buffer: &mut [u8], mut callback: F) -> Result<usize, E>
+        where F: FnMut(&[u8]) -> Result<ControlFlow<()>, E>,
+              E: From<Error>
+    {
+        let mut read_total = 0usize;
+
+        loop {
+            let read = try!(self.read(buffer));
+            read_total += read;
+
+            if read == 0 || try!(callback(&buffer[..read])) == ControlFlow::Break(()) {
+                break;
+            }
+        }
+
+        Ok(read_total)
+    }
+}
+
+// Failures that can occur while detecting or streaming an archive
+#[derive(Debug)]
+pub enum StreamError {
+    // Underlying read or decode failure
+    Io(Error),
+    // No known archive type could be detected for the named input
+    UnknownType(String),
+    // The archive type was recognized but can't be streamed
+    UnsupportedType(ArchiveType),
+    // The detection tooling itself couldn't be run
+    Detection,
+    // The destination didn't accept a decompressed block
+    Write(Error),
+    // An archive member would be written outside of the extraction directory
+    UnsafePath(String),
+    // The archive has no member of the requested name
+    MemberNotFound(String),
+    // The input has no data at all
+    Empty,
+    // The input ended in the middle of the archive
+    Truncated,
+    // A checksum or integrity check of the archive data failed
+    Corrupt(String),
+    // The archive or the named member needs a password to be read
+    Encrypted(String),
+    // The password given doesn't decrypt the named member
+    BadPassword(String),
+    // More than the given number of bytes were decompressed
+    TooLarge(u64),
+    // The data expanded by at least the given ratio, past the ratio limit
+    RatioExceeded(u64),
+    // Archives of this type can only be converted by the blocking API
+    RequiresSync(ArchiveType),
+    // An appended archive has a member named like one already appended
+    Collision(String),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StreamError::Io(ref e) => write!(f, "{}", e),
+            StreamError::UnknownType(ref name) => write!(f, "Unknown file type for '{}'", name),
+            StreamError::UnsupportedType(typ) => write!(f, "Unsupported file type '{}'", typ),
+            StreamError::Detection => write!(f, "Unable to run file(1) to detect the file type"),
+            StreamError::Write(ref e) => write!(f, "Unable to write decompressed block: {}", e),
+            StreamError::UnsafePath(ref name) => {
+                write!(f, "Refusing unsafe member path '{}' in a hostile archive", name)
+            }
+            StreamError::MemberNotFound(ref name) => {
+                write!(f, "No member named '{}' in the archive", name)
+            }
+            StreamError::Empty => write!(f, "Input is empty"),
+            StreamError::Truncated => write!(f, "Unexpected end of archive"),
+            StreamError::Corrupt(ref reason) => write!(f, "Corrupt archive: {}", reason),
+            StreamError::Encrypted(ref name) => {
+                write!(f, "'{}' is encrypted, pass --password or --password-file to decrypt \
+                           zip members",
+                       name)
+            }
+            StreamError::BadPassword(ref name) => write!(f, "Wrong password for '{}'", name),
+            StreamError::TooLarge(max) => {
+                write!(f, "Decompressed data exceeds the limit of {} bytes", max)
+            }
+            StreamError::RatioExceeded(ratio) => {
+                write!(f, "Decompressed data expanded {}:1, past the ratio limit", ratio)
+            }
+            StreamError::RequiresSync(typ) => {
+                write!(f, "Converting '{}' archives requires the blocking API", typ)
+            }
+            StreamError::Collision(ref name) => {
+                write!(f, "Member '{}' is in more than one of the appended archives", name)
+            }
+        }
+    }
+}
+
+impl StreamError {
+    // Exit code of the command line tool failing with the error, distinct
+    // for every kind of failure so scripts can tell them apart. The tool
+    // keeps 15 for skipped members, 64 for invalid usage and 130 for Ctrl-C
+    // to itself, see the README
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            StreamError::Io(ref e) if e.kind() == ErrorKind::NotFound => 17,
+            StreamError::Io(_) => 1,
+            StreamError::UnknownType(_) => 2,
+            StreamError::UnsupportedType(_) | StreamError::RequiresSync(_) => 3,
+            StreamError::Detection => 4,
+            StreamError::Write(_) => 5,
+            StreamError::UnsafePath(_) => 6,
+            StreamError::MemberNotFound(_) => 7,
+            StreamError::Empty => 8,
+            StreamError::Truncated => 9,
+            StreamError::Corrupt(_) => 10,
+            StreamError::Encrypted(_) => 11,
+            StreamError::TooLarge(_) => 12,
+            StreamError::RatioExceeded(_) => 13,
+            StreamError::BadPassword(_) => 14,
+            StreamError::Collision(_) => 16,
+        }
+    }
+}
+
+impl std::error::Error for StreamError {
+    fn source(&self) -> Option<&(std::error::Error + 'static)> {
+        match *self {
+            StreamError::Io(ref e) | StreamError::Write(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for StreamError {
+    fn from(e: Error) -> StreamError {
+        // Decoders running out of input mid-stream report UnexpectedEof
+        match e.kind() {
+            ErrorKind::UnexpectedEof => StreamError::Truncated,
+            _ => {
+                if let Some(c) = e.get_ref().and_then(|inner| inner.downcast_ref::<Corruption>()) {
+                    return StreamError::Corrupt(c.0.clone());
+                }
+                if let Some(l) = e.get_ref().and_then(|inner| inner.downcast_ref::<Exceeded>()) {
+                    return StreamError::TooLarge(l.0);
+                }
+                if let Some(r) = e.get_ref().and_then(|inner| inner.downcast_ref::<Expanded>()) {
+                    return StreamError::RatioExceeded(r.0);
+                }
+                if is_tar_checksum_mismatch(&e) {
+                    return StreamError::Corrupt(String::from(TAR_CHECKSUM_MISMATCH));
+                }
+                StreamError::Io(e)
+            }
+        }
+    }
+}
+
+// The tar crate reports a header failing its checksum as a plain error with
+// this message, at times wrapped in an error of its own
+const TAR_CHECKSUM_MISMATCH: &'static str = "archive header checksum mismatch";
+
+fn is_tar_checksum_mismatch(e: &Error) -> bool {
+    let mut cause: Option<&std::error::Error> = Some(e);
+
+    while let Some(error) = cause {
+        if error.to_string() == TAR_CHECKSUM_MISMATCH {
+            return true;
+        }
+        cause = error.source();
+    }
+
+    false
+}
+
+impl From<ZipError> for StreamError {
+    fn from(e: ZipError) -> StreamError {
+        match e {
+            ZipError::Io(e) => StreamError::from(e),
+            ZipError::InvalidArchive(reason) => StreamError::Corrupt(String::from(reason)),
+            _ => StreamError::Io(e.into()),
+        }
+    }
+}
+
+#[cfg(feature = "rar")]
+impl From<unrar::error::UnrarError> for StreamError {
+    fn from(e: unrar::error::UnrarError) -> StreamError {
+        use unrar::error::{Code, When};
+
+        match (e.code, e.when) {
+            (Code::BadData, _) => StreamError::Corrupt(e.to_string()),
+            (Code::MissingPassword, _) |
+            (Code::BadPassword, _) |
+            (Code::UnknownFormat, When::Open) => StreamError::Encrypted(String::from("archive")),
+            (Code::EndArchive, _) => StreamError::Truncated,
+            _ => StreamError::Io(Error::new(ErrorKind::Other, e.to_string())),
+        }
+    }
+}
+
+impl From<sevenz_rust::Error> for StreamError {
+    fn from(e: sevenz_rust::Error) -> StreamError {
+        use sevenz_rust::Error as SevenZError;
+
+        match e {
+            SevenZError::Io(e, _) |
+            SevenZError::FileOpen(e, _) => StreamError::from(e),
+            SevenZError::PasswordRequired |
+            SevenZError::MaybeBadPassword(_) => StreamError::Encrypted(String::from("archive")),
+            // Decryption isn't built in, so AES shows up as an unknown method
+            SevenZError::UnsupportedCompressionMethod(ref method) if method.starts_with("AES") => {
+                StreamError::Encrypted(String::from("archive"))
+            }
+            SevenZError::ChecksumVerificationFailed |
+            SevenZError::NextHeaderCrcMismatch => StreamError::Corrupt(e.to_string()),
+            _ => StreamError::Io(Error::new(ErrorKind::Other, e.to_string())),
+        }
+    }
+}
+
+// Marks an io::Error raised by a failed integrity check, see From<Error>
+#[derive(Debug)]
+struct Corruption(String);
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Corruption {}
+
+// Decoders and zip entries report checksum mismatches and malformed data with
+// whatever error kind their crate picked, anything that isn't an OS error or
+// the input running out is taken as corruption
+struct VerifiedReader<R> {
+    inner: R,
+}
+
+impl<R: Read> Read for VerifiedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf).map_err(|e| {
+            if e.raw_os_error().is_some() || e.kind() == ErrorKind::UnexpectedEof ||
+               e.kind() == ErrorKind::Interrupted {
+                e
+            } else {
+                Error::new(ErrorKind::InvalidData, Corruption(e.to_string()))
+            }
+        })
+    }
+}
+
+pub struct ArchiveClass<'a> {
+    pub class: ArchiveType,
+    pub type_name: &'a str,
+    // Alternative mime types `file --mime-type` reports for the format
+    pub mime: &'a [&'a str],
+    // Alternative magic byte signatures, all found at magic_offset
+    pub magic: &'a [&'a [u8]],
+    pub magic_offset: usize,
+}
+
+#[allow(non_upper_case_globals)]
+pub static Archives: [ArchiveClass<'static>; 16] = [ArchiveClass {
+                                                   class: TAR,
+                                                   type_name: "tar",
+                                                   mime: &["application/x-tar"],
+                                                   magic: &[b"ustar"],
+                                                   magic_offset: 257,
+                                               },
+                                               ArchiveClass {
+                                                   class: GZIP,
+                                                   type_name: "gzip",
+                                                   mime: &["application/gzip",
+                                                           "application/x-gzip"],
+                                                   magic: &[&[0x1f, 0x8b]],
+                                                   magic_offset: 0,
+                                               },
+                                               ArchiveClass {
+                                                   class: ZIP,
+                                                   type_name: "zip",
+                                                   mime: &["application/zip"],
+                                                   magic: &[b"PK\x03\x04", b"PK\x05\x06"],
+                                                   magic_offset: 0,
+                                               },
+                                               ArchiveClass {
+                                                   class: XZ,
+                                                   type_name: "xz",
+                                                   mime: &["application/x-xz"],
+                                                   magic: &[&[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00]],
+                                                   magic_offset: 0,
+                                               },
+                                               ArchiveClass {
+                                                   class: BZIP2,
+                                                   type_name: "bzip2",
+                                                   mime: &["application/x-bzip2"],
+                                                   magic: &[b"BZh"],
+                                                   magic_offset: 0,
+                                               },
+                                               ArchiveClass {
+                                                   class: ZSTD,
+                                                   type_name: "zstd",
+                                                   mime: &["application/zstd",
+                                                           "application/x-zstd"],
+                                                   magic: &[&[0x28, 0xb5, 0x2f, 0xfd]],
+                                                   magic_offset: 0,
+                                               },
+                                               ArchiveClass {
+                                                   class: LZ4,
+                                                   type_name: "lz4",
+                                                   mime: &["application/x-lz4"],
+                                                   magic: &[&[0x04, 0x22, 0x4d, 0x18], &[0x02, 0x21, 0x4c, 0x18]],
+                                                   magic_offset: 0,
+                                               },
+                                               ArchiveClass {
+                                                   class: LZMA,
+                                                   type_name: "lzma",
+                                                   mime: &["application/x-lzma"],
+                                                   // Properties byte of the default lc=3 lp=0 pb=2
+                                                   // and the low bytes of the dictionary size
+                                                   magic: &[&[0x5d, 0x00, 0x00]],
+                                                   magic_offset: 0,
+                                               },
+                                               ArchiveClass {
+                                                   class: LZIP,
+                                                   type_name: "lzip",
+                                                   mime: &["application/x-lzip"],
+                                                   magic: &[b"LZIP"],
+                                                   magic_offset: 0,
+                                               },
+                                               ArchiveClass {
+                                                   class: LZOP,
+                                                   type_name: "lzop",
+                                                   mime: &["application/x-lzop"],
+                                                   magic: &[&[0x89, b'L', b'Z', b'O']],
+                                                   magic_offset: 0,
+                                               },
+                                               ArchiveClass {
+                                                   class: COMPRESS,
+                                                   type_name: "compress",
+                                                   mime: &["application/x-compress"],
+                                                   magic: &[&[0x1f, 0x9d]],
+                                                   magic_offset: 0,
+                                               },
+                                               ArchiveClass {
+                                                   class: RAR,
+                                                   type_name: "rar",
+                                                   mime: &["application/x-rar",
+                                                           "application/vnd.rar"],
+                                                   magic: &[b"Rar!\x1a\x07"],
+                                                   magic_offset: 0,
+                                               },
+                                               ArchiveClass {
+                                                   class: SEVENZIP,
+                                                   type_name: "7z",
+                                                   mime: &["application/x-7z-compressed"],
+                                                   magic: &[&[0x37, 0x7a, 0xbc, 0xaf, 0x27, 0x1c]],
+                                                   magic_offset: 0,
+                                               },
+                                               ArchiveClass {
+                                                   class: CPIO,
+                                                   type_name: "cpio",
+                                                   mime: &["application/x-cpio"],
+                                                   // newc, newc with checksums and odc
+                                                   magic: &[b"070701", b"070702", b"070707"],
+                                                   magic_offset: 0,
+                                               },
+                                               ArchiveClass {
+                                                   class: AR,
+                                                   type_name: "ar",
+                                                   mime: &["application/x-archive",
+                                                           "application/vnd.debian.binary-package"],
+                                                   magic: &[b"!<arch>\n"],
+                                                   magic_offset: 0,
+                                               },
+                                               ArchiveClass {
+                                                   class: BROTLI,
+                                                   type_name: "brotli",
+                                                   mime: &["application/x-brotli"],
+                                                   // Brotli streams have no magic at all
+                                                   magic: &[],
+                                                   magic_offset: 0,
+                                               }];
+
+impl ArchiveType {
+    // The types set from the outermost layer in, the way they're decoded:
+    // compression first, then the archive it holds with Tar last, e.g. gzip
+    // wrapping tar. Several compressions or archives only come together in a
+    // list of fallbacks and keep the order of the Archives table
+    pub fn layers(&self) -> Vec<ArchiveType> {
+        let compressed = Archives.iter().filter(|p| is_compressed(p.class));
+        let archives = Archives.iter().filter(|p| !is_compressed(p.class) && p.class != TAR);
+
+        compressed.chain(archives)
+            .map(|p| p.class)
+            .chain(Some(TAR))
+            .filter(|&class| self.contains(class))
+            .collect()
+    }
+
+    // The outermost layer, INVALID if there's none
+    pub fn primary(&self) -> ArchiveType {
+        self.layers().first().cloned().unwrap_or(INVALID)
+    }
+}
+
+// Names of the layers of the type, the outer one first as in "gzip, tar"
+impl fmt::Display for ArchiveType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names: Vec<String> = self.layers().iter().map(type_name).collect();
+
+        if self.contains(AUTO) {
+            names.push(String::from("auto"));
+        }
+
+        if names.is_empty() {
+            write!(f, "unknown")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+// Name in the Archives table of a single type
+fn type_name(class: &ArchiveType) -> String {
+    Archives.iter().find(|p| p.class == *class).map_or(String::new(), |p| p.type_name.to_string())
+}
+
+// Number of leading bytes needed to recognize every magic in Archives,
+// the furthest one being the ustar magic at offset 257
+pub const SNIFF_SIZE: usize = 264;
+
+// Default size of the processing block, 16mb
+pub const DEFAULT_BLOCK_SIZE: usize = 1 << 24;
+
+// Smallest processing block picked for small source files, 64kb
+pub const MIN_BLOCK_SIZE: usize = 1 << 16;
+
+// Magic number of the LZ4 legacy frame format (`lz4 -l`), little endian
+const LZ4_LEGACY_MAGIC: u32 = 0x184C2102;
+// Legacy frame blocks always decompress to at most 8 MiB
+const LZ4_LEGACY_BLOCK_SIZE: usize = 8 << 20;
+
+// Decoder for the LZ4 legacy frame format, which the lz4 crate's frame
+// decoder doesn't understand. Expects the reader to be positioned right
+// after the leading magic number
+struct Lz4LegacyDecoder<R: Read> {
+    inner: R,
+    block: Vec<u8>,
+    compressed: Vec<u8>,
+    pos: usize,
+    len: usize,
+}
+
+impl<R: Read> Lz4LegacyDecoder<R> {
+    fn new(inner: R) -> Lz4LegacyDecoder<R> {
+        Lz4LegacyDecoder {
+            inner: inner,
+            block: vec!(0u8; LZ4_LEGACY_BLOCK_SIZE),
+            compressed: Vec::new(),
+            pos: 0,
+            len: 0,
+        }
+    }
+
+    // Read the little endian block size, returns None on a clean end of stream
+    fn read_block_size(&mut self) -> Result<Option<u32>, Error> {
+        let mut size = [0u8; 4];
+
+        match try!(read_head(&mut self.inner, &mut size)) {
+            0 => return Ok(None),
+            4 => {}
+            _ => return Err(Error::new(ErrorKind::UnexpectedEof, "truncated lz4 block header")),
+        }
+
+        Ok(Some((size[0] as u32) | (size[1] as u32) << 8 | (size[2] as u32) << 16 |
+                (size[3] as u32) << 24))
+    }
+
+    // Decompress the next block into the internal buffer, returns false at end of stream
+    fn next_block(&mut self) -> Result<bool, Error> {
+        loop {
+            let size = match try!(self.read_block_size()) {
+                Some(size) => size,
+                None => return Ok(false),
+            };
+
+            // Concatenated legacy streams simply repeat the magic number
+            if size == LZ4_LEGACY_MAGIC {
+                continue;
+            }
+
+            self.compressed.resize(size as usize, 0);
+            try!(self.inner.read_exact(&mut self.compressed));
+            self.len = try!(lz4::block::decompress_to_buffer(&self.compressed,
+                                                             Some(LZ4_LEGACY_BLOCK_SIZE as i32),
+                                                             &mut self.block));
+            self.pos = 0;
+
+            return Ok(true);
+        }
+    }
+}
+
+impl<R: Read> Read for Lz4LegacyDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.pos == self.len {
+            if !try!(self.next_block()) {
+                return Ok(0);
+            }
+        }
+
+        let count = std::cmp::min(buf.len(), self.len - self.pos);
+        buf[..count].copy_from_slice(&self.block[self.pos..self.pos + count]);
+        self.pos += count;
+
+        Ok(count)
+    }
+}
+
+// The lz4 crate's frame decoder quietly stops at the end of the input, even in
+// the middle of a frame, so check that the frame was complete
+struct Lz4FrameDecoder<R: Read> {
+    inner: Option<Lz4Decoder<R>>,
+}
+
+impl<R: Read> Read for Lz4FrameDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let read = match self.inner {
+            Some(ref mut decoder) => try!(decoder.read(buf)),
+            None => return Ok(0),
+        };
+
+        if read == 0 && !buf.is_empty() {
+            let (_, finished) = self.inner.take().unwrap().finish();
+
+            if finished.is_err() {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "truncated lz4 frame"));
+            }
+        }
+
+        Ok(read)
+    }
+}
+
+// Version of the lzip member format, the only one there is
+const LZIP_VERSION: u8 = 1;
+// Bytes of the member header, the magic, version and coded dictionary size
+const LZIP_HEADER_SIZE: usize = 6;
+// Bytes of the member trailer, CRC32, data size and member size
+const LZIP_TRAILER_SIZE: usize = 20;
+
+// Decoder for lzip files. A member is plain LZMA data with fixed lc=3 lp=0
+// pb=2 and an end marker, so it's decoded by the .lzma decoder of xz2 fed
+// a made up .lzma header of unknown size. xz2's liblzma predates lzip support.
+// Members may be concatenated like gzip ones, each trailer is checked
+struct LzipDecoder<R: Read> {
+    inner: BufReader<R>,
+    stream: Option<Stream>,
+    // Made up .lzma header not yet fed to the stream
+    head: Vec<u8>,
+    crc: Crc,
+    members: u64,
+}
+
+impl<R: Read> LzipDecoder<R> {
+    fn new(inner: R) -> LzipDecoder<R> {
+        LzipDecoder {
+            inner: BufReader::new(inner),
+            stream: None,
+            head: Vec::new(),
+            crc: Crc::new(),
+            members: 0,
+        }
+    }
+
+    // Read the next member header and set up its stream, returns false at end of input
+    fn next_member(&mut self) -> Result<bool, Error> {
+        let mut header = [0u8; LZIP_HEADER_SIZE];
+
+        match try!(read_head(&mut self.inner, &mut header)) {
+            0 if self.members > 0 => return Ok(false),
+            LZIP_HEADER_SIZE if &header[..4] == b"LZIP" => {}
+            _ if self.members > 0 => {
+                return Err(Error::new(ErrorKind::InvalidData, "trailing data after lzip member"))
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, "not an lzip member")),
+        }
+
+        if header[4] != LZIP_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("unsupported lzip version {}", header[4])));
+        }
+
+        // A power of two less up to seven sixteenths of it
+        let base = 1u32.checked_shl((header[5] & 0x1f) as u32).unwrap_or(0);
+        let dict_size = base - (base / 16) * (header[5] >> 5) as u32;
+
+        if dict_size < (1 << 12) || dict_size > (1 << 29) {
+            return Err(Error::new(ErrorKind::InvalidData, "invalid lzip dictionary size"));
+        }
+
+        self.head = vec![0x5d];
+        self.head.extend_from_slice(&[dict_size as u8,
+                                      (dict_size >> 8) as u8,
+                                      (dict_size >> 16) as u8,
+                                      (dict_size >> 24) as u8]);
+        self.head.extend_from_slice(&[0xff; 8]);
+        self.stream = Some(try!(Stream::new_lzma_decoder(std::u64::MAX).map_err(Error::from)));
+        self.crc.reset();
+        self.members += 1;
+
+        Ok(true)
+    }
+
+    // Check the trailer of the member the stream just ended
+    fn finish_member(&mut self) -> Result<(), Error> {
+        let stream = self.stream.take().unwrap();
+        let mut trailer = [0u8; LZIP_TRAILER_SIZE];
+
+        if try!(read_head(&mut self.inner, &mut trailer)) != LZIP_TRAILER_SIZE {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "truncated lzip trailer"));
+        }
+
+        let le = |bytes: &[u8]| bytes.iter().rev().fold(0u64, |n, &b| n << 8 | b as u64);
+        // Less the made up header, which isn't part of the member
+        let member_size = stream.total_in() - 13 + (LZIP_HEADER_SIZE + LZIP_TRAILER_SIZE) as u64;
+
+        if le(&trailer[..4]) != self.crc.sum() as u64 || le(&trailer[4..12]) != stream.total_out() ||
+           le(&trailer[12..]) != member_size {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  Corruption(String::from("lzip trailer doesn't match the data"))));
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for LzipDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            if self.stream.is_none() && !try!(self.next_member()) {
+                return Ok(0);
+            }
+
+            let (read, status) = {
+                let stream = self.stream.as_mut().unwrap();
+                let from_head = !self.head.is_empty();
+                let input = if from_head {
+                    &self.head[..]
+                } else {
+                    try!(self.inner.fill_buf())
+                };
+                let action = if input.is_empty() { Action::Finish } else { Action::Run };
+                let (before_in, before_out) = (stream.total_in(), stream.total_out());
+                let status = try!(stream.process(input, buf, action).map_err(Error::from));
+                let consumed = (stream.total_in() - before_in) as usize;
+                let read = (stream.total_out() - before_out) as usize;
+
+                if input.is_empty() && read == 0 && status != Status::StreamEnd {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "truncated lzip member"));
+                }
+
+                if from_head {
+                    self.head.drain(..consumed);
+                } else {
+                    self.inner.consume(consumed);
+                }
+
+                (read, status)
+            };
+
+            self.crc.update(&buf[..read]);
+
+            if status == Status::StreamEnd {
+                try!(self.finish_member());
+            }
+
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+        }
+    }
+}
+
+// Magic of lzop files, the Archives entry has the first four bytes
+const LZOP_MAGIC: &'static [u8] = b"\x89LZO\x00\r\n\x1a\n";
+// Oldest lzop header layout, the one with a level and 64-bit mtime
+const LZOP_MIN_VERSION: u16 = 0x0940;
+// Header flags, as in lzop's conf.h
+const LZOP_ADLER32_D: u32 = 0x1;
+const LZOP_ADLER32_C: u32 = 0x2;
+const LZOP_EXTRA_FIELD: u32 = 0x40;
+const LZOP_CRC32_D: u32 = 0x100;
+const LZOP_CRC32_C: u32 = 0x200;
+const LZOP_FILTER: u32 = 0x800;
+const LZOP_HEADER_CRC32: u32 = 0x1000;
+// Largest block lzop reads, it writes 256KiB ones
+const LZOP_MAX_BLOCK_SIZE: u32 = 64 << 20;
+
+// Adler-32 as lzop computes it, starting out at 1 like zlib's
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+
+    // The most bytes to add up before the sums can overflow
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+
+    b << 16 | a
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+
+    crc.update(data);
+    crc.sum()
+}
+
+fn read_u32_be<R: Read>(reader: &mut R) -> Result<u32, Error> {
+    let mut bytes = [0u8; 4];
+
+    try!(reader.read_exact(&mut bytes));
+    Ok(bytes.iter().fold(0, |n, &b| n << 8 | b as u32))
+}
+
+// Decoder for lzop files. The header is followed by blocks of LZO1X data,
+// each with the size before and after compression and the checksums the
+// header flags ask for, up to a block of size 0. Blocks that didn't get
+// smaller are stored as they are
+struct LzopDecoder<R: Read> {
+    inner: BufReader<R>,
+    flags: u32,
+    block: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> LzopDecoder<R> {
+    // Read and check the header of the file
+    fn new(inner: R) -> Result<LzopDecoder<R>, Error> {
+        let mut inner = BufReader::new(inner);
+        let mut magic = [0u8; 9];
+
+        try!(inner.read_exact(&mut magic));
+        if magic != LZOP_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not an lzop file"));
+        }
+
+        // Version, library version, version needed, method, level and flags
+        let mut header = vec![0u8; 12];
+        try!(inner.read_exact(&mut header));
+
+        let version = (header[0] as u16) << 8 | header[1] as u16;
+        let method = header[6];
+        let flags = header[8..12].iter().fold(0, |n, &b| n << 8 | b as u32);
+
+        if version < LZOP_MIN_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("unsupported lzop version {:x}", version)));
+        }
+        // LZO1X-1, LZO1X-1(15) and LZO1X-999, which all decode the same way
+        if method < 1 || method > 3 {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("unsupported lzop method {}", method)));
+        }
+        if flags & LZOP_FILTER != 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "lzop filters aren't supported"));
+        }
+
+        // Mode, mtime and the length of the name, then the name itself
+        let mut rest = [0u8; 13];
+        try!(inner.read_exact(&mut rest));
+        header.extend_from_slice(&rest);
+
+        let mut name = vec![0u8; rest[12] as usize];
+        try!(inner.read_exact(&mut name));
+        header.extend_from_slice(&name);
+
+        let checksum = if flags & LZOP_HEADER_CRC32 != 0 {
+            crc32(&header)
+        } else {
+            adler32(&header)
+        };
+
+        if try!(read_u32_be(&mut inner)) != checksum {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  Corruption(String::from("lzop header checksum mismatch"))));
+        }
+
+        // Nothing writes one, it's skipped along with its checksum
+        if flags & LZOP_EXTRA_FIELD != 0 {
+            let size = try!(read_u32_be(&mut inner)) as u64;
+            let skipped = try!(std::io::copy(&mut (&mut inner).take(size + 4),
+                                             &mut std::io::sink()));
+
+            if skipped != size + 4 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "truncated lzop header"));
+            }
+        }
+
+        Ok(LzopDecoder {
+            inner: inner,
+            flags: flags,
+            block: Vec::new(),
+            pos: 0,
+            done: false,
+        })
+    }
+
+    // Read the checksums given flags ask for, Adler-32 first
+    fn read_checksums(&mut self,
+                      adler: u32,
+                      crc: u32)
+                      -> Result<(Option<u32>, Option<u32>), Error> {
+        let adler = match self.flags & adler {
+            0 => None,
+            _ => Some(try!(read_u32_be(&mut self.inner))),
+        };
+        let crc = match self.flags & crc {
+            0 => None,
+            _ => Some(try!(read_u32_be(&mut self.inner))),
+        };
+
+        Ok((adler, crc))
+    }
+
+    // Decode the next block into self.block, returns false at the end marker
+    fn next_block(&mut self) -> Result<bool, Error> {
+        let size = try!(read_u32_be(&mut self.inner));
+
+        if size == 0 {
+            return Ok(false);
+        }
+
+        let compressed = try!(read_u32_be(&mut self.inner));
+
+        if size > LZOP_MAX_BLOCK_SIZE || compressed > size {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  Corruption(String::from("invalid lzop block size"))));
+        }
+
+        let checksums = try!(self.read_checksums(LZOP_ADLER32_D, LZOP_CRC32_D));
+        let compressed_checksums = if compressed < size {
+            try!(self.read_checksums(LZOP_ADLER32_C, LZOP_CRC32_C))
+        } else {
+            (None, None)
+        };
+
+        let mut data = vec![0u8; compressed as usize];
+        try!(self.inner.read_exact(&mut data));
+
+        let matches = |data: &[u8], (adler, crc): (Option<u32>, Option<u32>)| {
+            adler.map_or(true, |sum| adler32(data) == sum) &&
+            crc.map_or(true, |sum| crc32(data) == sum)
+        };
+
+        if !matches(&data, compressed_checksums) {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  Corruption(String::from("lzop block checksum mismatch"))));
+        }
+
+        if compressed < size {
+            self.block.resize(size as usize, 0);
+
+            let decoded = {
+                let (out, result) = LZOContext::decompress_to_slice(&data, &mut self.block);
+                result == LZOError::OK && out.len() == size as usize
+            };
+
+            if !decoded {
+                return Err(Error::new(ErrorKind::InvalidData,
+                                      Corruption(String::from("invalid lzop block data"))));
+            }
+        } else {
+            self.block = data;
+        }
+
+        if !matches(&self.block, checksums) {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  Corruption(String::from("lzop block checksum mismatch"))));
+        }
+
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for LzopDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.pos == self.block.len() {
+            if self.done || !try!(self.next_block()) {
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let count = std::cmp::min(buf.len(), self.block.len() - self.pos);
+
+        buf[..count].copy_from_slice(&self.block[self.pos..self.pos + count]);
+        self.pos += count;
+
+        Ok(count)
+    }
+}
+
+// Code width compress(1) starts out with
+const LZW_INIT_BITS: u32 = 9;
+// Widest code compress(1) can produce
+const LZW_MAX_BITS: u32 = 16;
+// Code which resets the dictionary in block mode
+const LZW_CLEAR: u32 = 256;
+
+// Decoder for the LZW streams of Unix compress(1), the .Z files. Codes are
+// packed least significant bit first and grow from 9 bits up to the maximum
+// recorded in the header. Whenever the width changes or the dictionary is
+// cleared, compress(1) skips to the end of the current group of 8 codes
+struct LzwDecoder<R: Read> {
+    inner: BufReader<R>,
+    max_bits: u32,
+    block_mode: bool,
+    bits: u32,
+    bit_buf: u32,
+    bit_count: u32,
+    // Bits consumed since the start of the current group of codes
+    group_pos: u64,
+    free_ent: u32,
+    old_code: Option<u32>,
+    fin_char: u8,
+    prefix: Vec<u16>,
+    suffix: Vec<u8>,
+    stack: Vec<u8>,
+    out: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> LzwDecoder<R> {
+    fn new(inner: R) -> Result<LzwDecoder<R>, Error> {
+        let mut inner = BufReader::new(inner);
+        let mut header = [0u8; 3];
+
+        if try!(read_head(&mut inner, &mut header)) != header.len() ||
+           header[..2] != [0x1f, 0x9d] {
+            return Err(Error::new(ErrorKind::InvalidData, "missing compress header"));
+        }
+
+        let max_bits = (header[2] & 0x1f) as u32;
+
+        if max_bits < LZW_INIT_BITS || max_bits > LZW_MAX_BITS {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("unsupported compress code width of {} bits", max_bits)));
+        }
+
+        let block_mode = header[2] & 0x80 != 0;
+
+        Ok(LzwDecoder {
+            inner: inner,
+            max_bits: max_bits,
+            block_mode: block_mode,
+            bits: LZW_INIT_BITS,
+            bit_buf: 0,
+            bit_count: 0,
+            group_pos: 0,
+            free_ent: if block_mode { LZW_CLEAR + 1 } else { LZW_CLEAR },
+            old_code: None,
+            fin_char: 0,
+            prefix: vec!(0u16; 1 << LZW_MAX_BITS),
+            suffix: (0..1u32 << LZW_MAX_BITS).map(|c| c as u8).collect(),
+            stack: Vec::new(),
+            out: Vec::new(),
+            pos: 0,
+            done: false,
+        })
+    }
+
+    // Pull in another input byte, returns false at end of stream
+    fn fill_byte(&mut self) -> Result<bool, Error> {
+        let mut byte = [0u8; 1];
+
+        if try!(read_head(&mut self.inner, &mut byte)) == 0 {
+            return Ok(false);
+        }
+
+        self.bit_buf |= (byte[0] as u32) << self.bit_count;
+        self.bit_count += 8;
+
+        Ok(true)
+    }
+
+    // Read the next code, returns None once fewer bits than a code are left
+    fn read_code(&mut self) -> Result<Option<u32>, Error> {
+        while self.bit_count < self.bits {
+            if !try!(self.fill_byte()) {
+                return Ok(None);
+            }
+        }
+
+        let code = self.bit_buf & ((1 << self.bits) - 1);
+        self.bit_buf >>= self.bits;
+        self.bit_count -= self.bits;
+        self.group_pos += self.bits as u64;
+
+        Ok(Some(code))
+    }
+
+    // Skip the rest of the current group of 8 codes and switch to a new width
+    fn start_group(&mut self, bits: u32) -> Result<(), Error> {
+        let group = self.bits as u64 * 8;
+        let mut skip = (group - self.group_pos % group) % group;
+
+        while skip > 0 {
+            if self.bit_count == 0 && !try!(self.fill_byte()) {
+                break;
+            }
+
+            let count = std::cmp::min(skip, self.bit_count as u64) as u32;
+            self.bit_buf >>= count;
+            self.bit_count -= count;
+            skip -= count as u64;
+        }
+
+        self.bits = bits;
+        self.group_pos = 0;
+
+        Ok(())
+    }
+
+    // Decode codes until there's some output, returns false at end of stream
+    fn next_string(&mut self) -> Result<bool, Error> {
+        loop {
+            // The dictionary outgrew the code width
+            if self.bits < self.max_bits && self.free_ent >= 1 << self.bits {
+                let bits = self.bits + 1;
+                try!(self.start_group(bits));
+            }
+
+            let code = match try!(self.read_code()) {
+                Some(code) => code,
+                None => return Ok(false),
+            };
+
+            let old_code = match self.old_code {
+                Some(old_code) => old_code,
+                None => {
+                    if code >= LZW_CLEAR {
+                        return Err(Error::new(ErrorKind::InvalidData, "corrupt compress data"));
+                    }
+
+                    self.old_code = Some(code);
+                    self.fin_char = code as u8;
+                    self.out.clear();
+                    self.out.push(self.fin_char);
+                    self.pos = 0;
+
+                    return Ok(true);
+                }
+            };
+
+            if code == LZW_CLEAR && self.block_mode {
+                self.free_ent = LZW_CLEAR;
+                try!(self.start_group(LZW_INIT_BITS));
+                continue;
+            }
+
+            // A code one past the dictionary repeats the previous string
+            // followed by its own first character
+            let mut cur = code;
+            self.stack.clear();
+
+            if cur >= self.free_ent {
+                if cur > self.free_ent {
+                    return Err(Error::new(ErrorKind::InvalidData, "corrupt compress data"));
+                }
+
+                self.stack.push(self.fin_char);
+                cur = old_code;
+            }
+
+            while cur >= LZW_CLEAR {
+                self.stack.push(self.suffix[cur as usize]);
+                cur = self.prefix[cur as usize] as u32;
+            }
+
+            self.fin_char = self.suffix[cur as usize];
+            self.stack.push(self.fin_char);
+
+            if self.free_ent < 1 << self.max_bits {
+                self.prefix[self.free_ent as usize] = old_code as u16;
+                self.suffix[self.free_ent as usize] = self.fin_char;
+                self.free_ent += 1;
+            }
+
+            self.old_code = Some(code);
+            self.out.clear();
+            self.out.extend(self.stack.iter().rev());
+            self.pos = 0;
+
+            return Ok(true);
+        }
+    }
+}
+
+impl<R: Read> Read for LzwDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.pos == self.out.len() {
+            if self.done || !try!(self.next_string()) {
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let count = std::cmp::min(buf.len(), self.out.len() - self.pos);
+        buf[..count].copy_from_slice(&self.out[self.pos..self.pos + count]);
+        self.pos += count;
+
+        Ok(count)
+    }
+}
+
+// If name ends with suffix then set a bitflag in flags
+fn set_suffix_flag(name: &str, suffix: &str, flags: &mut ArchiveType, set: ArchiveType) {
+    if name.ends_with(suffix) {
+        *flags |= set;
+    }
+}
+
+// Fill buffer from reader as far as possible, returning the number of bytes read
+fn read_head<R: Read>(reader: &mut R, head: &mut [u8]) -> Result<usize, Error> {
+    let mut filled = 0;
+
+    while filled < head.len() {
+        match try!(reader.read(&mut head[filled..])) {
+            0 => break,
+            read => filled += read,
+        }
+    }
+
+    Ok(filled)
+}
+
+// Match the leading bytes of a file against the magic signatures in Archives
+pub fn magic_type(head: &[u8]) -> ArchiveType {
+    let mut typ = INVALID;
+
+    for class in Archives.iter() {
+        for magic in class.magic.iter() {
+            let end = class.magic_offset + magic.len();
+
+            if head.len() >= end && &head[class.magic_offset..end] == *magic {
+                typ |= class.class;
+            }
+        }
+    }
+
+    typ
+}
+
+// Get type of the archive from its magic bytes, consumes up to SNIFF_SIZE
+// bytes from the reader
+pub fn sniff_magic<R: Read>(reader: &mut R) -> ArchiveType {
+    let mut head = [0u8; SNIFF_SIZE];
+
+    match read_head(reader, &mut head) {
+        Ok(read) => magic_type(&head[..read]),
+        Err(_) => INVALID,
+    }
+}
+
+// Get type of the archive from its magic bytes without losing them, returns
+// the type along with a reader yielding the complete original stream
+pub fn peek_archive_type<R: Read>(mut reader: R)
+                                  -> Result<(ArchiveType, Chain<Cursor<Vec<u8>>, R>), Error> {
+    let mut head = vec!(0u8; SNIFF_SIZE);
+    let read = try!(read_head(&mut reader, &mut head));
+    head.truncate(read);
+
+    let typ = magic_type(&head);
+
+    Ok((typ, Cursor::new(head).chain(reader)))
+}
+
+// Header layouts of Tar archives, told apart by the first header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TarVariant {
+    // Unix V7 headers, there's no magic
+    V7,
+    // POSIX ustar headers
+    Ustar,
+    // POSIX ustar headers starting out with PAX records
+    Pax,
+    // GNU headers, the ustar magic with a blank version
+    Gnu,
+}
+
+impl TarVariant {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            TarVariant::V7 => "v7",
+            TarVariant::Ustar => "ustar",
+            TarVariant::Pax => "pax",
+            TarVariant::Gnu => "gnu",
+        }
+    }
+}
+
+// Variant of the Tar header in block, None if it isn't one. Without a magic
+// to go by V7 headers have to add up to their checksum
+pub fn tar_variant(block: &[u8]) -> Option<TarVariant> {
+    if block.len() < 512 {
+        return None;
+    }
+
+    match (&block[257..263], &block[263..265], block[156]) {
+        (b"ustar\0", b"00", b'x') | (b"ustar\0", b"00", b'g') => Some(TarVariant::Pax),
+        (b"ustar\0", b"00", _) => Some(TarVariant::Ustar),
+        (b"ustar ", b" \0", _) => Some(TarVariant::Gnu),
+        _ => {
+            // The checksum counts its own field as spaces
+            let sum = block[..512]
+                .iter()
+                .enumerate()
+                .fold(0, |sum, (i, &b)| sum + if i >= 148 && i < 156 { 32 } else { b as u32 });
+            let stored = std::str::from_utf8(&block[148..156])
+                .ok()
+                .map(|field| field.trim_matches(|c| c == ' ' || c == '\0'))
+                .and_then(|field| u32::from_str_radix(field, 8).ok());
+
+            match block[0] != 0 && stored == Some(sum) {
+                true => Some(TarVariant::V7),
+                false => None,
+            }
+        }
+    }
+}
+
+// Variant of the Tar archive of type typ in input, decompressed first if need
+// be, None if it doesn't start with a Tar header
+pub fn read_tar_variant<R: Read>(input: R,
+                                 typ: ArchiveType)
+                                 -> Result<Option<TarVariant>, StreamError> {
+    let options = StreamOptions::default();
+    let (_, mut payload) = try!(unwrap_payload(input, typ, &options));
+    let mut block = [0u8; 512];
+    let read = try!(read_head(&mut payload, &mut block));
+
+    Ok(tar_variant(&block[..read]))
+}
+
+// Same as peek_archive_type() but an explicit archive_type other than INVALID
+// takes precedence over the sniffed one, and empty input is an error
+fn peek_input<R: Read>(reader: R,
+                       archive_type: ArchiveType)
+                       -> Result<(ArchiveType, Chain<Cursor<Vec<u8>>, R>), StreamError> {
+    let (typ, input) = try!(peek_archive_type(reader));
+
+    if input.get_ref().0.get_ref().is_empty() {
+        return Err(StreamError::Empty);
+    }
+
+    Ok((resolve_type(archive_type, typ), input))
+}
+
+// Settle on the requested archive type or the detected one. INVALID and AUTO
+// go by detection, the types listed along with AUTO only stand in when
+// nothing could be detected. Any other request overrides detection
+pub fn resolve_type(requested: ArchiveType, detected: ArchiveType) -> ArchiveType {
+    if requested == INVALID || requested.contains(AUTO) && detected != INVALID {
+        detected
+    } else {
+        requested - AUTO
+    }
+}
+
+// Fail with StreamError::Empty for a zero-byte file
+fn check_not_empty(file: &File) -> Result<(), StreamError> {
+    if try!(file.metadata()).len() == 0 {
+        return Err(StreamError::Empty);
+    }
+
+    Ok(())
+}
+
+// Add flags implied by the file name on top of the detected type
+fn set_filename_flags(path: &str, typ: &mut ArchiveType) {
+    // Only the file name counts, a directory like backups.zip says nothing
+    // about the files inside it
+    let name = path.rsplit('/').next().unwrap_or(path);
+    // What's left without the last extension, as in the .tar of .tar.gz
+    let inner = name.rfind('.').map_or("", |dot| &name[..dot]);
+
+    // If the extension is .tar, or the one under the compression's is, or
+    // it's .tgz, .tbz, .txz, .tzst, .tlz, .taz, .tzo or .tZ classify the file
+    // as Tar
+    if name.ends_with(".tar") || inner.ends_with(".tar") {
+        *typ |= TAR;
+    }
+    set_suffix_flag(name, ".tgz", typ, TAR | GZIP);
+    set_suffix_flag(name, ".tbz", typ, TAR | BZIP2);
+    set_suffix_flag(name, ".tbz2", typ, TAR | BZIP2);
+    set_suffix_flag(name, ".txz", typ, TAR | XZ);
+    set_suffix_flag(name, ".tzst", typ, TAR | ZSTD);
+    set_suffix_flag(name, ".tlz", typ, TAR | LZMA);
+    set_suffix_flag(name, ".taz", typ, TAR | COMPRESS);
+    set_suffix_flag(name, ".tzo", typ, TAR | LZOP);
+    set_suffix_flag(name, ".tZ", typ, TAR | COMPRESS);
+
+    set_suffix_flag(name, ".gz", typ, GZIP);
+    set_suffix_flag(name, ".bz2", typ, BZIP2);
+    set_suffix_flag(name, ".xz", typ, XZ);
+    set_suffix_flag(name, ".zst", typ, ZSTD);
+    set_suffix_flag(name, ".lz4", typ, LZ4);
+    set_suffix_flag(name, ".lzma", typ, LZMA);
+    set_suffix_flag(name, ".lz", typ, LZIP);
+    set_suffix_flag(name, ".lzo", typ, LZOP);
+    set_suffix_flag(name, ".Z", typ, COMPRESS);
+    set_suffix_flag(name, ".br", typ, BROTLI);
+    set_suffix_flag(name, ".zip", typ, ZIP);
+    set_suffix_flag(name, ".rar", typ, RAR);
+    set_suffix_flag(name, ".7z", typ, SEVENZIP);
+    set_suffix_flag(name, ".cpio", typ, CPIO);
+    set_suffix_flag(name, ".deb", typ, AR);
+}
+
+// Type of the archive identified by the mime type file(1) reports
+fn mime_type(mime: &str) -> ArchiveType {
+    let mut typ = INVALID;
+
+    for class in Archives.iter() {
+        if class.mime.contains(&mime) {
+            typ |= class.class;
+        }
+    }
+
+    typ
+}
+
+// Get type of the archive by sniffing its magic bytes and filename heuristics,
+// the file(1) tool is only consulted when the magic is inconclusive. The
+// content wins over a filename claiming a different format, the name only
+// decides for content that couldn't be identified and tells whether a
+// compressed payload is a Tar archive
+pub fn get_archive_type(path: &str, verbose: bool) -> Result<ArchiveType, StreamError> {
+    get_archive_type_with(path, "file", verbose)
+}
+
+// Same as get_archive_type() with file_bin run in place of file(1), which
+// may live outside of PATH or behind a wrapper
+pub fn get_archive_type_with(path: &str,
+                             file_bin: &str,
+                             verbose: bool)
+                             -> Result<ArchiveType, StreamError> {
+    let typ = try!(detect_archive_type(path, file_bin, verbose));
+
+    if verbose && typ != INVALID {
+        let layers: Vec<String> = typ.layers().iter().map(type_name).collect();
+
+        eprintln!("Detected {} as {}", path, layers.join(" wrapping "));
+    }
+
+    Ok(typ)
+}
+
+fn detect_archive_type(path: &str,
+                       file_bin: &str,
+                       verbose: bool)
+                       -> Result<ArchiveType, StreamError> {
+    let mut typ = match File::open(path) {
+        Ok(mut file) => {
+            try!(check_not_empty(&file));
+            sniff_magic(&mut file)
+        }
+        Err(_) => INVALID,
+    };
+
+    let mut named = INVALID;
+    set_filename_flags(path, &mut named);
+
+    if typ == INVALID {
+        // Mime types don't change with the wording of the descriptions,
+        // the C locale keeps error messages in place of one untranslated
+        let output = Command::new(file_bin)
+            .args(&["--mime-type", "-b", "--"])
+            .arg(path)
+            .env("LANG", "C")
+            .env("LC_ALL", "C")
+            .output();
+
+        match output {
+            Ok(output) => typ = mime_type(String::from_utf8_lossy(&output.stdout).trim()),
+            Err(e) => {
+                if verbose {
+                    eprintln!("Unable to run {}: {}", file_bin, e);
+                }
+
+                // Formats without magic bytes, brotli, still go by the name
+                if named == INVALID {
+                    return Err(StreamError::Detection);
+                }
+            }
+        }
+    }
+
+    if typ == INVALID {
+        return Ok(named);
+    }
+
+    let mismatch = named - TAR - typ;
+
+    if verbose && !mismatch.is_empty() {
+        eprintln!("Warning: {} looks like {} but its name suggests {}, going by the content",
+                  path,
+                  typ,
+                  mismatch);
+    }
+
+    if is_compressed(typ) {
+        typ |= named & TAR;
+    }
+
+    Ok(typ)
+}
+
+// Metadata of a single archive member as reported by list()
+#[derive(Serialize)]
+pub struct ListEntry {
+    pub name: String,
+    pub size: u64,
+    pub mode: u32,
+    // Unix timestamp, 0 if the archive doesn't record a usable one
+    pub mtime: u64,
+    pub is_dir: bool,
+    // Size of the stored data, None for Tar members which aren't compressed
+    // one by one
+    pub compressed_size: Option<u64>,
+}
+
+// Member written to the Tar archive as recorded by a Manifest, the digest is
+// the hex digest of its data if the manifest hashes members
+#[derive(Serialize, Clone)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub size: u64,
+    pub mode: u32,
+    pub digest: Option<String>,
+}
+
+// Tunables for streaming an archive
+pub struct StreamOptions {
+    // Size of the processing block in bytes
+    pub block_size: usize,
+    // How many times -v was given: 1 prints the detected format to stderr,
+    // 2 traces every block and converted member as well
+    pub verbosity: u8,
+    // Keep member paths which are absolute or contain '..' components
+    pub allow_unsafe_paths: bool,
+    // Number of threads decompressing zip members concurrently
+    pub threads: usize,
+    // Report how much of the source has been read to stderr
+    pub progress: Option<Progress>,
+    // Compress the output with GZIP, BZIP2, XZ or ZSTD, INVALID leaves it as is
+    pub recompress: ArchiveType,
+    // Compression level for recompress, None picks the codec's default
+    pub level: Option<u32>,
+    // Leading path components dropped from members converted from zips or
+    // extracted, members with nothing left are skipped
+    pub strip_components: usize,
+    // Only convert or extract members matching one of these, all if empty
+    pub include: Vec<Pattern>,
+    // Leave out members matching any of these, even if they're included
+    pub exclude: Vec<Pattern>,
+    // Hash everything written to the output before recompressing it
+    pub checksum: Option<Checksum>,
+    // Convert ar members which are Tar archives into the members they hold
+    pub recurse: bool,
+    // Give up once this many bytes have been decompressed, None for no limit
+    pub max_size: Option<SizeLimit>,
+    // Write just this many bytes of the decompressed stream and stop decoding
+    // there, None writes all of it. Archives converted member by member
+    // don't have such a stream
+    pub head: Option<u64>,
+    // Give up once compressed data or a zip member expands more than this
+    // many times, None for no limit
+    pub ratio_limit: Option<u64>,
+    // Header layout of the members converted from other archive formats
+    pub tar_format: TarFormat,
+    // Legacy encoding of zip member names lacking the UTF-8 flag, None
+    // decodes them as CP437
+    pub encoding: Option<&'static Encoding>,
+    // Rewrite the paths of converted members, applied one after another
+    pub transforms: Vec<Transform>,
+    // Write zip members with the same data as an earlier one as hard links
+    pub dedup_hardlinks: bool,
+    // Read regular file sources through a memory map instead of read calls
+    pub mmap: bool,
+    // Carry zip archive and member comments over as PAX records
+    pub keep_comments: bool,
+    // Compression layers to decode at most, more than one if compressed data
+    // may hold compressed data again
+    pub decode_layers: usize,
+    // Record the members written to the Tar archive, which parses Tar
+    // archives member by member instead of copying them
+    pub manifest: Option<Manifest>,
+    // Password of ZipCrypto or AES encrypted zip members, which are refused
+    // without one
+    pub password: Option<Vec<u8>>,
+    // Leave out zip members whose data can't be read and record them instead
+    // of giving up on the conversion
+    pub skip_errors: Option<SkippedEntries>,
+    // Uid and gid every member gets instead of its own, None keeps them
+    pub owner: Option<u32>,
+    pub group: Option<u32>,
+    // Leave the user and group names out of the members, just ids are kept
+    pub numeric_owner: bool,
+    // Permission bits of every regular file and every directory member
+    // instead of their own, None keeps them
+    pub file_mode: Option<u32>,
+    pub dir_mode: Option<u32>,
+    // Recompute the CRC-32 of zip member data and fail naming the member
+    // whose CRC-32 doesn't match the one the archive records
+    pub verify_crc: bool,
+}
+
+impl StreamOptions {
+    pub fn verbose(&self) -> bool {
+        self.verbosity > 0
+    }
+}
+
+impl Default for StreamOptions {
+    fn default() -> StreamOptions {
+        StreamOptions {
+            block_size: DEFAULT_BLOCK_SIZE,
+            verbosity: 0,
+            allow_unsafe_paths: false,
+            threads: 1,
+            progress: None,
+            recompress: INVALID,
+            level: None,
+            strip_components: 0,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            checksum: None,
+            recurse: false,
+            max_size: None,
+            head: None,
+            ratio_limit: None,
+            tar_format: TarFormat::Gnu,
+            encoding: None,
+            transforms: Vec::new(),
+            dedup_hardlinks: false,
+            mmap: false,
+            keep_comments: false,
+            decode_layers: 1,
+            manifest: None,
+            password: None,
+            skip_errors: None,
+            owner: None,
+            group: None,
+            numeric_owner: false,
+            file_mode: None,
+            dir_mode: None,
+            verify_crc: false,
+        }
+    }
+}
+
+// Minimum time between two progress reports
+const PROGRESS_INTERVAL_MS: u64 = 250;
+
+// Running total of source bytes read, printed to stderr so that stdout stays
+// usable for the output. Reports are throttled to a few per second
+pub struct Progress {
+    total: Option<u64>,
+    start: Instant,
+    state: Mutex<ProgressState>,
+}
+
+struct ProgressState {
+    read: u64,
+    reported: Option<Instant>,
+}
+
+impl Progress {
+    // Total is the size of the source if known, enabling percentage and ETA
+    pub fn new(total: Option<u64>) -> Progress {
+        Progress {
+            total: total,
+            start: Instant::now(),
+            state: Mutex::new(ProgressState {
+                read: 0,
+                reported: None,
+            }),
+        }
+    }
+
+    // Account for bytes read from the source, reporting if it's due
+    pub fn add(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        state.read += bytes;
+
+        let due = match state.reported {
+            Some(last) => now.duration_since(last) >= Duration::from_millis(PROGRESS_INTERVAL_MS),
+            None => true,
+        };
+
+        if due {
+            state.reported = Some(now);
+            self.report(state.read, now);
+        }
+    }
+
+    // Print the final totals and end the progress line
+    pub fn finish(&self) {
+        let state = self.state.lock().unwrap();
+        self.report(state.read, Instant::now());
+        eprintln!("");
+    }
+
+    fn report(&self, read: u64, now: Instant) {
+        let mib = |bytes: u64| bytes as f64 / (1 << 20) as f64;
+
+        match self.total {
+            Some(total) if total > 0 => {
+                // Zip central directory reads can take us past the file size
+                let read = std::cmp::min(read, total);
+                let elapsed = now.duration_since(self.start).as_secs_f64();
+                let eta = if read > 0 {
+                    (elapsed * (total - read) as f64 / read as f64) as u64
+                } else {
+                    0
+                };
+
+                eprint!("\r{:.1} / {:.1} MiB read ({:3}%), ETA {}:{:02}  ",
+                        mib(read),
+                        mib(total),
+                        read * 100 / total,
+                        eta / 60,
+                        eta % 60);
+            }
+            _ => eprint!("\r{:.1} MiB read  ", mib(read)),
+        }
+    }
+}
+
+// Reader passing everything through while counting the bytes into progress
+struct ProgressReader<'a, R> {
+    inner: R,
+    progress: Option<&'a Progress>,
+}
+
+impl<'a, R> ProgressReader<'a, R> {
+    fn new(inner: R, progress: Option<&'a Progress>) -> ProgressReader<'a, R> {
+        ProgressReader {
+            inner: inner,
+            progress: progress,
+        }
+    }
+}
+
+impl<'a, R: Read> Read for ProgressReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let read = try!(self.inner.read(buf));
+
+        if let Some(progress) = self.progress {
+            progress.add(read as u64);
+        }
+
+        Ok(read)
+    }
+}
+
+impl<'a, R: Seek> Seek for ProgressReader<'a, R> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        self.inner.seek(pos)
+    }
+}
+
+// State of the hash algorithm picked for a Checksum
+#[derive(Clone)]
+enum Hasher {
+    Sha256(Sha256),
+    Sha1(Sha1),
+    Md5(Md5),
+}
+
+// Running digest of the produced Tar archive or decompressed payload, shared
+// the same way as Progress
+pub struct Checksum {
+    state: Mutex<Hasher>,
+}
+
+impl Checksum {
+    // Algorithm is one of sha256, sha1 or md5, None for anything else
+    pub fn new(algorithm: &str) -> Option<Checksum> {
+        let hasher = match algorithm.to_lowercase().as_str() {
+            "sha256" => Hasher::Sha256(Sha256::new()),
+            "sha1" => Hasher::Sha1(Sha1::new()),
+            "md5" => Hasher::Md5(Md5::new()),
+            _ => return None,
+        };
+
+        Some(Checksum { state: Mutex::new(hasher) })
+    }
+
+    fn update(&self, data: &[u8]) {
+        match *self.state.lock().unwrap() {
+            Hasher::Sha256(ref mut hasher) => hasher.update(data),
+            Hasher::Sha1(ref mut hasher) => hasher.update(data),
+            Hasher::Md5(ref mut hasher) => hasher.update(data),
+        }
+    }
+
+    // Hex digest of everything hashed so far
+    pub fn hex_digest(&self) -> String {
+        let digest = match self.state.lock().unwrap().clone() {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha1(hasher) => hasher.finalize().to_vec(),
+            Hasher::Md5(hasher) => hasher.finalize().to_vec(),
+        };
+
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+// Members written to the Tar archive in the order they were written, shared
+// the same way as Progress
+pub struct Manifest {
+    algorithm: Option<String>,
+    entries: Mutex<Vec<ManifestEntry>>,
+}
+
+impl Manifest {
+    // Hash the data of every member with algorithm as well, it's one of those
+    // Checksum::new() takes
+    pub fn new(algorithm: Option<&str>) -> Manifest {
+        Manifest {
+            algorithm: algorithm.map(|algorithm| algorithm.to_string()),
+            entries: Mutex::new(Vec::new()),
+        }
+    }
+
+    // A fresh Checksum for the data of the next member, None if not hashing
+    fn checksum(&self) -> Option<Checksum> {
+        self.algorithm.as_ref().and_then(|algorithm| Checksum::new(algorithm))
+    }
+
+    fn record(&self, name: &str, size: u64, mode: u32, checksum: Option<Checksum>) {
+        self.entries.lock().unwrap().push(ManifestEntry {
+            name: name.to_string(),
+            size: size,
+            mode: mode,
+            digest: checksum.map(|checksum| checksum.hex_digest()),
+        });
+    }
+
+    // Entries recorded so far
+    pub fn entries(&self) -> Vec<ManifestEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+}
+
+// Zip member left out of a conversion and the error reading it failed with
+#[derive(Clone)]
+pub struct SkippedEntry {
+    pub name: String,
+    pub reason: String,
+}
+
+// Members skip_errors left out in the order they were met, shared the same
+// way as Progress
+pub struct SkippedEntries {
+    entries: Mutex<Vec<SkippedEntry>>,
+}
+
+impl SkippedEntries {
+    pub fn new() -> SkippedEntries {
+        SkippedEntries { entries: Mutex::new(Vec::new()) }
+    }
+
+    fn record(&self, name: String, e: &StreamError) {
+        eprintln!("Warning: skipping '{}': {}", name, e);
+
+        self.entries.lock().unwrap().push(SkippedEntry {
+            name: name,
+            reason: e.to_string(),
+        });
+    }
+
+    // Entries recorded so far
+    pub fn entries(&self) -> Vec<SkippedEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+}
+
+// Cap on the number of bytes decompressed by one conversion or extraction,
+// counted across all members of an archive
+pub struct SizeLimit {
+    max: u64,
+    total: Mutex<u64>,
+}
+
+impl SizeLimit {
+    pub fn new(max: u64) -> SizeLimit {
+        SizeLimit {
+            max: max,
+            total: Mutex::new(0),
+        }
+    }
+
+    // Account for count more decompressed bytes, failing once over the cap
+    fn consume(&self, count: u64) -> Result<(), Error> {
+        let mut total = self.total.lock().unwrap();
+        *total += count;
+
+        if *total > self.max {
+            return Err(Error::new(ErrorKind::Other, Exceeded(self.max)));
+        }
+
+        Ok(())
+    }
+}
+
+// Marks the io::Error raised by SizeLimit, see From<Error>
+#[derive(Debug)]
+struct Exceeded(u64);
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "decompressed data exceeds the limit of {} bytes", self.0)
+    }
+}
+
+impl std::error::Error for Exceeded {}
+
+// Reader of decompressed data counting against the size limit, if any
+struct LimitedReader<'a, R> {
+    inner: R,
+    limit: Option<&'a SizeLimit>,
+}
+
+impl<'a, R: Read> Read for LimitedReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = try!(self.inner.read(buf));
+
+        if let Some(limit) = self.limit {
+            try!(limit.consume(count as u64));
+        }
+
+        Ok(count)
+    }
+}
+
+// Wrap a reader of decompressed data in the size limit of options
+fn limited<'a, R: Read>(inner: R, options: &'a StreamOptions) -> LimitedReader<'a, R> {
+    LimitedReader {
+        inner: inner,
+        limit: options.max_size.as_ref(),
+    }
+}
+
+// Marks the io::Error raised by RatioReader, see From<Error>
+#[derive(Debug)]
+struct Expanded(u64);
+
+impl fmt::Display for Expanded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "decompressed data expanded {}:1", self.0)
+    }
+}
+
+impl std::error::Error for Expanded {}
+
+// Reader counting the compressed bytes a decoder consumes
+struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = try!(self.inner.read(buf));
+        self.count.set(self.count.get() + count as u64);
+
+        Ok(count)
+    }
+}
+
+impl<R: Seek> Seek for CountingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+// Reader of decompressed data failing once it's more than limit times the
+// compressed bytes counted in input so far. Decoders read ahead, which only
+// makes the ratio come out lower at the start
+struct RatioReader<R> {
+    inner: R,
+    input: Rc<Cell<u64>>,
+    output: u64,
+    limit: Option<u64>,
+}
+
+impl<R: Read> Read for RatioReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = try!(self.inner.read(buf));
+        self.output += count as u64;
+
+        if let Some(limit) = self.limit {
+            let input = std::cmp::max(self.input.get(), 1);
+
+            if self.output > limit.saturating_mul(input) {
+                return Err(Error::new(ErrorKind::Other, Expanded(self.output / input)));
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+// Reader checking that the CRC-32 of a zip member's data is the one the
+// archive records, once all of its size has been read. The zip crate checks
+// it too, without telling which member failed or what the CRC-32 came out as
+struct CrcCheckedReader<R> {
+    inner: R,
+    crc: Crc,
+    read: u64,
+    // Name of the member, its recorded CRC-32 and size, None checks nothing
+    expected: Option<(String, u32, u64)>,
+}
+
+impl<R: Read> Read for CrcCheckedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let result = self.inner.read(buf);
+        let (name, crc, size) = match self.expected {
+            Some((ref name, crc, size)) => (name, crc, size),
+            None => return result,
+        };
+
+        // The zip crate fails the read that would end the data on a mismatch
+        let ended = match result {
+            Ok(0) => !buf.is_empty(),
+            Ok(count) => {
+                self.crc.update(&buf[..count]);
+                self.read += count as u64;
+                false
+            }
+            Err(ref e) => e.raw_os_error().is_none() && e.kind() != ErrorKind::Interrupted,
+        };
+
+        if ended && self.read == size && self.crc.sum() != crc {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  Corruption(format!("CRC-32 of '{}' is {:08x}, the archive \
+                                                      records {:08x}",
+                                                     name,
+                                                     self.crc.sum(),
+                                                     crc))));
+        }
+
+        result
+    }
+}
+
+// Reader handed out by zip_contents()
+type ZipContents<'a, 'b, 'c> =
+    LimitedReader<'c, RatioReader<VerifiedReader<CrcCheckedReader<&'a mut ZipFile<'b>>>>>;
+
+// Decompressed contents of a zip member, checked for corruption and held to
+// the size and ratio limits of options. The sizes recorded in the archive
+// are checked up front, the data itself as it's read
+fn zip_contents<'a, 'b, 'c>(zf: &'a mut ZipFile<'b>,
+                            options: &'c StreamOptions)
+                            -> Result<ZipContents<'a, 'b, 'c>, StreamError> {
+    let compressed = zf.compressed_size();
+
+    if let Some(limit) = options.ratio_limit {
+        let input = std::cmp::max(compressed, 1);
+
+        if zf.size() > limit.saturating_mul(input) {
+            return Err(StreamError::RatioExceeded(zf.size() / input));
+        }
+    }
+
+    // AE-2 encrypted members record a CRC-32 of 0 and rely on their MAC
+    let expected = match (options.verify_crc, zf.crc32()) {
+        (true, 0) if has_extra_field(zf.extra_data(), EXTRA_AES) => None,
+        (true, crc) => Some((zip_name(zf, options), crc, zf.size())),
+        (false, _) => None,
+    };
+    let contents = RatioReader {
+        inner: VerifiedReader {
+            inner: CrcCheckedReader {
+                inner: zf,
+                crc: Crc::new(),
+                read: 0,
+                expected: expected,
+            },
+        },
+        input: Rc::new(Cell::new(compressed)),
+        output: 0,
+        limit: options.ratio_limit,
+    };
+
+    Ok(limited(contents, options))
+}
+
+// Writer passing everything through while hashing the accepted bytes
+struct ChecksumWriter<'a, W> {
+    inner: W,
+    checksum: Option<&'a Checksum>,
+}
+
+impl<'a, W: Write> Write for ChecksumWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let written = try!(self.inner.write(buf));
+
+        if let Some(checksum) = self.checksum {
+            checksum.update(&buf[..written]);
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+}
+
+// Reader passing everything through while hashing it
+struct ChecksumReader<'a, R> {
+    inner: R,
+    checksum: Option<&'a Checksum>,
+}
+
+impl<'a, R: Read> Read for ChecksumReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let read = try!(self.inner.read(buf));
+
+        if let Some(checksum) = self.checksum {
+            checksum.update(&buf[..read]);
+        }
+
+        Ok(read)
+    }
+}
+
+// Write decompressed data from decoder into destination by using the provided
+// buffer, up to options.head bytes of it. Returns the number of bytes written
+fn decode_file_into<T: Chunked, W: Write>(buffer: &mut [u8],
+                                          dst: &mut W,
+                                          mut decoder: T,
+                                          options: &StreamOptions)
+                                          -> Result<u64, StreamError> {
+    let head = options.head.unwrap_or(std::u64::MAX);
+    let mut written = 0;
+
+    try!(decoder.chunked(buffer, |chunk| -> Result<_, StreamError> {
+        let chunk = &chunk[..std::cmp::min(chunk.len() as u64, head - written) as usize];
+
+        try!(dst.write_all(chunk).map_err(StreamError::Write));
+        written += chunk.len() as u64;
+
+        if options.verbosity >= 2 {
+            eprintln!("Read and wrote a block of {} bytes, {} in total", chunk.len(), written);
+        }
+
+        // Nothing past the head gets decoded
+        match written < head {
+            true => Ok(ControlFlow::Continue(())),
+            false => Ok(ControlFlow::Break(())),
+        }
+    }));
+
+    Ok(written)
+}
+
+// Archives converted member by member have no decompressed stream for
+// options.head to cut short
+fn check_no_head(typ: ArchiveType, options: &StreamOptions) -> Result<(), StreamError> {
+    match options.head {
+        Some(_) => Err(StreamError::UnsupportedType(typ)),
+        None => Ok(()),
+    }
+}
+
+// Returns true if the member path stays inside the directory it's extracted
+// into, i.e. it's relative and doesn't climb up with '..' components
+fn is_safe_path(path: &Path) -> bool {
+    path.components().all(|c| {
+        match c {
+            Component::Normal(_) | Component::CurDir => true,
+            _ => false,
+        }
+    })
+}
+
+// Drop the first count components of a member path the way GNU tar's
+// --strip-components does, None if nothing is left of it
+fn strip_components(name: &str, count: usize) -> Option<String> {
+    if count == 0 {
+        return Some(name.to_string());
+    }
+
+    let rest: Vec<String> = Path::new(name)
+        .components()
+        .filter(|c| {
+            match *c {
+                Component::Normal(_) | Component::ParentDir => true,
+                _ => false,
+            }
+        })
+        .skip(count)
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+
+    if rest.is_empty() {
+        return None;
+    }
+
+    // Keep the trailing slash zip uses to tell directories apart
+    let mut stripped = rest.join("/");
+    if name.ends_with('/') {
+        stripped.push('/');
+    }
+
+    Some(stripped)
+}
+
+// True if any include or exclude patterns were given
+fn is_filtering(options: &StreamOptions) -> bool {
+    !options.include.is_empty() || !options.exclude.is_empty()
+}
+
+// True if the owners or modes of members are rewritten by options
+fn is_rewriting_headers(options: &StreamOptions) -> bool {
+    options.owner.is_some() || options.group.is_some() || options.numeric_owner ||
+    options.file_mode.is_some() || options.dir_mode.is_some()
+}
+
+// Rewrite the owner and mode of a member the way options ask for. A name no
+// longer goes with an id that's replaced, so it's cleared along with it
+fn override_header(header: &mut Header, options: &StreamOptions) {
+    let kind = header.entry_type();
+    let mode = if kind.is_file() {
+        options.file_mode
+    } else if kind.is_dir() {
+        options.dir_mode
+    } else {
+        None
+    };
+
+    if let Some(mode) = mode {
+        header.set_mode(mode);
+    }
+    if let Some(uid) = options.owner {
+        header.set_uid(uid);
+    }
+    if let Some(gid) = options.group {
+        header.set_gid(gid);
+    }
+
+    // Old style headers have no names to clear
+    if options.owner.is_some() || options.numeric_owner {
+        let _ = header.set_username("");
+    }
+    if options.group.is_some() || options.numeric_owner {
+        let _ = header.set_groupname("");
+    }
+
+    header.set_cksum();
+}
+
+// True if Tar archives have to be gone through member by member rather than
+// copied, to filter them, record their members or rewrite their headers
+fn is_parsing_tar(options: &StreamOptions) -> bool {
+    is_filtering(options) || options.manifest.is_some() || is_rewriting_headers(options)
+}
+
+// Whether the member at name passes the include and exclude patterns, a
+// trailing slash of directories is ignored
+fn is_selected(name: &str, options: &StreamOptions) -> bool {
+    let name = name.trim_right_matches('/');
+
+    if options.exclude.iter().any(|pattern| pattern.matches(name)) {
+        return false;
+    }
+
+    options.include.is_empty() || options.include.iter().any(|pattern| pattern.matches(name))
+}
+
+// A GNU tar style s/REGEX/REPLACEMENT/FLAGS substitution on member paths.
+// REGEX is in the syntax of the regex crate, REPLACEMENT can refer to the
+// whole match with & and to groups with \1 to \9
+#[derive(Clone)]
+pub struct Transform {
+    regex: Regex,
+    replacement: String,
+    global: bool,
+}
+
+impl Transform {
+    // Parse the expression, any character following the s is the delimiter.
+    // The g flag replaces every match instead of the first, i ignores case
+    pub fn new(expression: &str) -> Result<Transform, String> {
+        let mut chars = expression.chars();
+
+        if chars.next() != Some('s') {
+            return Err(String::from("expected s/REGEX/REPLACEMENT/FLAGS"));
+        }
+
+        let delimiter = match chars.next() {
+            Some(c) if c != '\\' => c,
+            _ => return Err(String::from("missing delimiter after s")),
+        };
+        let mut parts = vec![String::new()];
+        let mut escaped = false;
+
+        for c in chars {
+            // An escaped delimiter stands for itself, other escapes are kept
+            if escaped {
+                if c != delimiter {
+                    parts.last_mut().unwrap().push('\\');
+                }
+                parts.last_mut().unwrap().push(c);
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == delimiter {
+                parts.push(String::new());
+            } else {
+                parts.last_mut().unwrap().push(c);
+            }
+        }
+
+        if parts.len() != 3 || escaped {
+            return Err(String::from("expected s/REGEX/REPLACEMENT/FLAGS"));
+        }
+
+        let mut builder = RegexBuilder::new(&parts[0]);
+        let mut global = false;
+
+        for flag in parts[2].chars() {
+            match flag {
+                'g' => global = true,
+                'i' => {
+                    builder.case_insensitive(true);
+                }
+                _ => return Err(format!("unsupported flag '{}'", flag)),
+            }
+        }
+
+        Ok(Transform {
+            regex: try!(builder.build().map_err(|e| e.to_string())),
+            replacement: sed_replacement(&parts[1]),
+            global: global,
+        })
+    }
+
+    fn apply(&self, name: &str) -> String {
+        if self.global {
+            self.regex.replace_all(name, self.replacement.as_str()).into_owned()
+        } else {
+            self.regex.replace(name, self.replacement.as_str()).into_owned()
+        }
+    }
+}
+
+// Turn a sed replacement into the regex crate's: & and \N refer to the
+// match and its groups, while $ is nothing special
+fn sed_replacement(replacement: &str) -> String {
+    let mut converted = String::new();
+    let mut chars = replacement.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => converted.push_str("${0}"),
+            '$' => converted.push_str("$$"),
+            '\\' => {
+                match chars.next() {
+                    Some(digit @ '0'...'9') => converted.push_str(&format!("${{{}}}", digit)),
+                    Some(other) => converted.push(other),
+                    None => converted.push('\\'),
+                }
+            }
+            _ => converted.push(c),
+        }
+    }
+
+    converted
+}
+
+// Path of a converted member once every transform of options has been applied
+fn transform_name(name: String, options: &StreamOptions) -> String {
+    options.transforms.iter().fold(name, |name, transform| transform.apply(&name))
+}
+
+// Copy a name verbatim into a header field, bypassing the checks of
+// Header::set_path and Header::set_link_name which refuse unsafe paths
+fn copy_raw_name(slot: &mut [u8], name: &str) -> Result<(), Error> {
+    if name.len() > slot.len() {
+        return Err(Error::new(ErrorKind::InvalidInput, "member path too long"));
+    }
+
+    slot[..name.len()].copy_from_slice(name.as_bytes());
+
+    Ok(())
+}
+
+// Mask and type bits of symbolic links in Unix modes
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFSOCK: u32 = 0o140000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFIFO: u32 = 0o010000;
+
+// Unix permissions of a zip entry, archives created by Windows tools don't
+// record any so fall back to the usual defaults for files and directories
+fn zip_mode(zf: &ZipFile) -> u32 {
+    match zf.unix_mode() {
+        Some(mode) => mode,
+        None if zf.is_dir() || zf.name().ends_with('/') => 0o755,
+        None => 0o644,
+    }
+}
+
+// Path of a zip entry. Names without the UTF-8 flag are decoded as CP437 by
+// the zip crate, which is what DOS and old Windows tools mostly used, but not
+// all of them, so those are decoded from the raw bytes in the encoding of
+// options if one is given
+fn zip_name(zf: &ZipFile, options: &StreamOptions) -> String {
+    match options.encoding {
+        // A UTF-8 name, or a plain ASCII one, is the same bytes either way,
+        // while CP437 maps every byte over 0x7f to a multibyte character
+        Some(encoding) if zf.name().as_bytes() != zf.name_raw() => {
+            encoding.decode_without_bom_handling(zf.name_raw()).0.into_owned()
+        }
+        _ => zf.name().to_string(),
+    }
+}
+
+// Convert the DOS timestamp of a zip entry into a Unix timestamp, zip
+// doesn't record a timezone so it's taken as UTC. Returns None for dates
+// that don't exist, such as the all-zero timestamp some tools write
+fn zip_mtime(zf: &ZipFile) -> Option<u64> {
+    match zf.last_modified().to_time() {
+        Ok(time) if time.unix_timestamp() >= 0 => Some(time.unix_timestamp() as u64),
+        _ => None,
+    }
+}
+
+// Info-ZIP extra fields carrying Unix metadata of a member
+const EXTRA_TIMESTAMP: u16 = 0x5455;
+const EXTRA_UNIX_OLD: u16 = 0x5855;
+const EXTRA_UNIX: u16 = 0x7875;
+
+// WinZip's extra field of AES encrypted zip members
+const EXTRA_AES: u16 = 0x9901;
+
+// Metadata found in the extra fields of a zip member
+#[derive(Default)]
+struct UnixExtra {
+    mtime: Option<u64>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+// Little endian integer of up to eight bytes
+fn read_le(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
+// Whether the extra fields of a zip member include one of the given id
+fn has_extra_field(data: &[u8], wanted: u16) -> bool {
+    let mut rest = data;
+
+    while rest.len() >= 4 {
+        let id = read_le(&rest[0..2]) as u16;
+        let len = read_le(&rest[2..4]) as usize;
+
+        if id == wanted {
+            return true;
+        }
+
+        rest = &rest[std::cmp::min(4 + len, rest.len())..];
+    }
+
+    false
+}
+
+// Walk the extra fields of a zip member looking for Unix times and
+// ownership. The extended timestamp and the newer Unix field take precedence
+// over the old Unix field, malformed fields are skipped
+fn zip_unix_extra(data: &[u8]) -> UnixExtra {
+    let mut extra = UnixExtra::default();
+    let mut old = UnixExtra::default();
+    let mut rest = data;
+
+    while rest.len() >= 4 {
+        let id = read_le(&rest[0..2]) as u16;
+        let len = read_le(&rest[2..4]) as usize;
+
+        if rest.len() < 4 + len {
+            break;
+        }
+
+        let field = &rest[4..4 + len];
+        rest = &rest[4 + len..];
+
+        match id {
+            // Flags followed by the times they announce, mtime first
+            EXTRA_TIMESTAMP if len >= 5 && field[0] & 1 != 0 => {
+                let time = read_le(&field[1..5]) as u32 as i32;
+
+                if time >= 0 {
+                    extra.mtime = Some(time as u64);
+                }
+            }
+            // Version, then the sizes and values of uid and gid
+            EXTRA_UNIX if len >= 3 && field[0] == 1 => {
+                let uid_size = field[1] as usize;
+
+                if uid_size > 8 || len < 3 + uid_size {
+                    continue;
+                }
+
+                let gid_size = field[2 + uid_size] as usize;
+                let gid_start = 3 + uid_size;
+
+                if gid_size > 8 || len < gid_start + gid_size {
+                    continue;
+                }
+
+                extra.uid = Some(read_le(&field[2..2 + uid_size]) as u32);
+                extra.gid = Some(read_le(&field[gid_start..gid_start + gid_size]) as u32);
+            }
+            // atime and mtime, the local header copy adds 16 bit uid and gid
+            EXTRA_UNIX_OLD if len >= 8 => {
+                old.mtime = Some(read_le(&field[4..8]));
+
+                if len >= 12 {
+                    old.uid = Some(read_le(&field[8..10]) as u32);
+                    old.gid = Some(read_le(&field[10..12]) as u32);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    UnixExtra {
+        mtime: extra.mtime.or(old.mtime),
+        uid: extra.uid.or(old.uid),
+        gid: extra.gid.or(old.gid),
+    }
+}
+
+// Build the tar header for a zip member, mtime, uid and gid are used for
+// members which don't carry their own in the extra fields or, for the
+// timestamp, a usable DOS time. Directory members become tar directories and
+// symlinks, stored by zip as a file holding the link target, become tar
+// symlinks. Neither has a payload
+fn zip_entry_header(zf: &mut ZipFile,
+                    mtime: u64,
+                    uid: u32,
+                    gid: u32,
+                    options: &StreamOptions)
+                    -> Result<Option<Member>, StreamError> {
+    let name = match strip_components(&zip_name(zf, options), options.strip_components) {
+        Some(ref name) if is_selected(name, options) => name.clone(),
+        _ => return Ok(None),
+    };
+    let mode = zip_mode(zf);
+
+    // Create a Tar header for each ZipFile
+    let mut member = try!(Member::new(name, options));
+
+    // Set file metadata in tar header
+    if zf.is_dir() {
+        member.header.set_entry_type(EntryType::Directory);
+        member.header.set_size(0);
+    } else if mode & S_IFMT == S_IFLNK {
+        let mut target = String::new();
+        try!(zf.read_to_string(&mut target));
+
+        member.set_link(EntryType::Symlink, target);
+    } else {
+        // Oversized members get their size from a PAX record instead
+        if zf.size() <= USTAR_MAX_SIZE {
+            member.header.set_size(zf.size());
+        } else {
+            member.header.set_size(0);
+        }
+    }
+
+    let extra = zip_unix_extra(zf.extra_data());
+
+    member.header.set_mode(mode);
+    member.header.set_mtime(extra.mtime.or_else(|| zip_mtime(zf)).unwrap_or(mtime));
+    member.header.set_uid(extra.uid.unwrap_or(uid));
+    member.header.set_gid(extra.gid.unwrap_or(gid));
+    member.header.set_cksum();
+
+    if options.keep_comments && !zf.comment().is_empty() {
+        member.comment = Some(zf.comment().to_string());
+    }
+
+    Ok(Some(member))
+}
+
+// Layout of the Tar archives converted from other archive formats
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TarFormat {
+    // GNU headers, long paths and link targets go into ././@LongLink entries
+    Gnu,
+    // POSIX ustar headers with PAX records for the fields that overflow
+    Pax,
+    // Plain POSIX ustar headers, members that don't fit are an error
+    Ustar,
+}
+
+impl TarFormat {
+    // Name is one of gnu, pax or ustar, None for anything else
+    pub fn from_name(name: &str) -> Option<TarFormat> {
+        match name.to_lowercase().as_str() {
+            "gnu" => Some(TarFormat::Gnu),
+            "pax" => Some(TarFormat::Pax),
+            "ustar" => Some(TarFormat::Ustar),
+            _ => None,
+        }
+    }
+}
+
+// Tar header of a converted member, along with the path and link target
+// that didn't fit into it and go into extension entries instead
+struct Member {
+    header: Header,
+    // Path the member is written under, after the transforms
+    path: String,
+    long_path: Option<String>,
+    long_link: Option<String>,
+    // Written as a PAX record, there's no header field for it
+    comment: Option<String>,
+}
+
+impl Member {
+    // Header for the member at path in the Tar format of options. Downstream
+    // consumers will extract the tar, so don't let a hostile archive smuggle
+    // in paths escaping the target directory
+    fn new(path: String, options: &StreamOptions) -> Result<Member, StreamError> {
+        let path = transform_name(path, options);
+        let mut member = Member {
+            header: match options.tar_format {
+                TarFormat::Gnu => Header::new_gnu(),
+                TarFormat::Pax | TarFormat::Ustar => Header::new_ustar(),
+            },
+            path: path.clone(),
+            long_path: None,
+            long_link: None,
+            comment: None,
+        };
+
+        if Path::new(&path).components().all(|c| c == Component::CurDir) {
+            // The tar crate wants a file name, the archive root directory that
+            // cpio and some zips record is written the way GNU tar does instead
+            try!(copy_raw_name(&mut member.header.as_old_mut().name, "./"));
+        } else if is_safe_path(Path::new(&path)) {
+            if member.header.set_path(Path::new(&path)).is_err() {
+                copy_truncated(&mut member.header.as_old_mut().name, &path);
+                member.long_path = Some(path);
+            }
+        } else if options.allow_unsafe_paths {
+            if copy_raw_name(&mut member.header.as_old_mut().name, &path).is_err() {
+                copy_truncated(&mut member.header.as_old_mut().name, &path);
+                member.long_path = Some(path);
+            }
+        } else {
+            return Err(StreamError::UnsafePath(path));
+        }
+
+        Ok(member)
+    }
+
+    // Make the member a symlink or hard link to target, relative links
+    // climbing up with '..' are legitimate
+    fn set_link(&mut self, kind: EntryType, target: String) {
+        self.header.set_entry_type(kind);
+        self.header.set_size(0);
+
+        if self.header.set_link_name(Path::new(&target)).is_err() &&
+           copy_raw_name(&mut self.header.as_old_mut().linkname, &target).is_err() {
+            copy_truncated(&mut self.header.as_old_mut().linkname, &target);
+            self.long_link = Some(target);
+        }
+    }
+}
+
+// Copy as much of a name as fits into a header field, for readers that
+// don't understand the extension entry carrying all of it
+fn copy_truncated(slot: &mut [u8], name: &str) {
+    let len = std::cmp::min(slot.len(), name.len());
+
+    slot[..len].copy_from_slice(&name.as_bytes()[..len]);
+}
+
+// Largest size the 11 octal digits of the ustar size field can hold, 8 GiB
+const USTAR_MAX_SIZE: u64 = 0o77777777777;
+
+// Format a PAX extended header record, which starts with its own length
+fn pax_record(key: &str, value: &str) -> String {
+    let body = format!(" {}={}\n", key, value);
+    let mut len = body.len() + body.len().to_string().len();
+
+    // Adding the length digits can make the length itself one digit longer
+    if body.len() + len.to_string().len() > len {
+        len += 1;
+    }
+
+    format!("{}{}", len, body)
+}
+
+// PAX keywords of zip comments, vendor keywords are spelled in capitals. The
+// archive comment has its own, a global header applies to every member and
+// readers would take it for the comment of members lacking one otherwise
+const PAX_COMMENT: &'static str = "TARSTREAMER.comment";
+const PAX_ARCHIVE_COMMENT: &'static str = "TARSTREAMER.archive_comment";
+
+// Append a PAX header entry of the given kind holding records, an extended
+// header ('x') applies to the next member and a global one ('g') to the rest
+// of the archive
+fn append_pax_header<W: Write>(builder: &mut Builder<W>,
+                               kind: EntryType,
+                               path: &str,
+                               records: &str)
+                               -> Result<(), Error> {
+    let mut header = Header::new_ustar();
+
+    try!(header.set_path(path));
+    header.set_entry_type(kind);
+    header.set_size(records.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+
+    builder.append(&header, records.as_bytes())
+}
+
+// Start the Tar archive with a global PAX header holding the comment of the
+// zip archive, if options keep comments and there is one
+fn append_zip_comment<R: Read + Seek, W: Write>(builder: &mut Builder<W>,
+                                                archive: &ZipArchive<R>,
+                                                options: &StreamOptions)
+                                                -> Result<(), StreamError> {
+    if !options.keep_comments || archive.comment().is_empty() {
+        return Ok(());
+    }
+
+    if options.tar_format == TarFormat::Ustar {
+        return Err(StreamError::Io(Error::new(ErrorKind::InvalidInput,
+                                              "ustar headers can't hold comments")));
+    }
+
+    // Like member names, archive comments are CP437 unless stated otherwise
+    let comment = match options.encoding {
+        Some(encoding) => encoding.decode_without_bom_handling(archive.comment()).0,
+        None => String::from_utf8_lossy(archive.comment()),
+    };
+
+    try!(append_pax_header(builder,
+                           EntryType::XGlobalHeader,
+                           "pax_global_header",
+                           &pax_record(PAX_ARCHIVE_COMMENT, &comment)));
+
+    Ok(())
+}
+
+// Append a GNU long name ('L') or long link ('K') entry holding name
+fn append_long_name<W: Write>(builder: &mut Builder<W>, kind: u8, name: &str) -> Result<(), Error> {
+    let mut header = Header::new_gnu();
+
+    try!(header.set_path("././@LongLink"));
+    header.set_entry_type(EntryType::new(kind));
+    header.set_size(name.len() as u64 + 1);
+    header.set_mode(0o644);
+    header.set_cksum();
+
+    builder.append(&header, name.as_bytes().chain(&[0u8][..]))
+}
+
+// Append a member of size bytes to the Tar archive, preceded by the entries
+// carrying what doesn't fit into its header: GNU long name and link entries
+// or PAX records, depending on the Tar format of options. Oversized members
+// get their size from a PAX record in GNU archives too, as GNU tar does it.
+// Plain ustar has no way around its limits and fails instead
+fn append_member<W: Write, R: Read>(builder: &mut Builder<W>,
+                                    member: &Member,
+                                    size: u64,
+                                    data: R,
+                                    options: &StreamOptions)
+                                    -> Result<(), StreamError> {
+    let mut records = String::new();
+
+    if options.verbosity >= 2 {
+        eprintln!("Appending {} of {} bytes, mode {:o}",
+                  member.path,
+                  size,
+                  member.header.mode().unwrap_or(0));
+    }
+
+    match options.tar_format {
+        TarFormat::Gnu => {
+            if let Some(ref path) = member.long_path {
+                try!(append_long_name(builder, b'L', path));
+            }
+            if let Some(ref link) = member.long_link {
+                try!(append_long_name(builder, b'K', link));
+            }
+        }
+        TarFormat::Pax => {
+            if let Some(ref path) = member.long_path {
+                records.push_str(&pax_record("path", path));
+            }
+            if let Some(ref link) = member.long_link {
+                records.push_str(&pax_record("linkpath", link));
+            }
+        }
+        TarFormat::Ustar => {
+            if let Some(name) = member.long_path.as_ref().or(member.long_link.as_ref()) {
+                let message = format!("'{}' is too long for a ustar header", name);
+                return Err(StreamError::Io(Error::new(ErrorKind::InvalidInput, message)));
+            }
+            if size > USTAR_MAX_SIZE {
+                let message = format!("{} bytes are too large for a ustar header", size);
+                return Err(StreamError::Io(Error::new(ErrorKind::InvalidInput, message)));
+            }
+            if member.comment.is_some() {
+                return Err(StreamError::Io(Error::new(ErrorKind::InvalidInput,
+                                                      "ustar headers can't hold comments")));
+            }
+        }
+    }
+
+    if size > USTAR_MAX_SIZE {
+        records.push_str(&pax_record("size", &size.to_string()));
+    }
+
+    if let Some(ref comment) = member.comment {
+        records.push_str(&pax_record(PAX_COMMENT, comment));
+    }
+
+    if !records.is_empty() {
+        try!(append_pax_header(builder, EntryType::XHeader, "././@PaxHeader", &records));
+    }
+
+    let mut header = member.header.clone();
+
+    if is_rewriting_headers(options) {
+        override_header(&mut header, options);
+    }
+
+    match options.manifest {
+        Some(ref manifest) => {
+            let checksum = manifest.checksum();
+
+            try!(builder.append(&header,
+                                ChecksumReader { inner: data, checksum: checksum.as_ref() }));
+            manifest.record(&member.path, size, header.mode().unwrap_or(0), checksum);
+        }
+        None => try!(builder.append(&header, data)),
+    }
+
+    Ok(())
+}
+
+// An earlier zip member considered for deduplication, the digest of its
+// data is only computed once another member has the same CRC-32 and size
+struct ZipContent {
+    index: usize,
+    path: String,
+    digest: Option<Vec<u8>>,
+}
+
+// Zip member at index, ready to be read. Encrypted members are decrypted with
+// the password of the options and refused by name without one, the zip crate
+// itself only says a password is required
+fn zip_member<'a, R: Read + Seek>(decoder: &'a mut ZipArchive<R>,
+                                  index: usize,
+                                  options: &StreamOptions)
+                                  -> Result<ZipFile<'a>, StreamError> {
+    let name = zip_name(&try!(decoder.by_index_raw(index)), options);
+    let opened = match options.password {
+        Some(ref password) => decoder.by_index_decrypt(index, password),
+        None => decoder.by_index(index).map(Ok),
+    };
+
+    match opened {
+        Ok(Ok(zf)) => Ok(zf),
+        Ok(Err(_)) => Err(StreamError::BadPassword(name)),
+        Err(ZipError::UnsupportedArchive(ZipError::PASSWORD_REQUIRED)) => {
+            Err(StreamError::Encrypted(name))
+        }
+        Err(e) => Err(StreamError::from(e)),
+    }
+}
+
+// SHA-256 digest of the data of the zip member at index
+fn zip_digest<R: Read + Seek>(decoder: &mut ZipArchive<R>,
+                              index: usize,
+                              options: &StreamOptions)
+                              -> Result<Vec<u8>, StreamError> {
+    let mut zf = try!(zip_member(decoder, index, options));
+    let mut hasher = Sha256::new();
+
+    try!(std::io::copy(&mut VerifiedReader { inner: &mut zf }, &mut hasher));
+
+    Ok(hasher.finalize().to_vec())
+}
+
+// Tar path of an earlier member with the same data as the member at index,
+// which is recorded for the members after it if there's none. A matching
+// CRC-32 and size make members candidates, the SHA-256 digests of their data
+// have to match as well
+fn find_duplicate<R: Read + Seek>(decoder: &mut ZipArchive<R>,
+                                  index: usize,
+                                  path: &str,
+                                  contents: &mut HashMap<(u32, u64), Vec<ZipContent>>,
+                                  options: &StreamOptions)
+                                  -> Result<Option<String>, StreamError> {
+    let key = {
+        let zf = try!(decoder.by_index_raw(index));
+        (zf.crc32(), zf.size())
+    };
+    let mut digest = None;
+
+    if let Some(candidates) = contents.get_mut(&key) {
+        let own = try!(zip_digest(decoder, index, options));
+
+        for candidate in candidates.iter_mut() {
+            if candidate.digest.is_none() {
+                candidate.digest = Some(try!(zip_digest(decoder, candidate.index, options)));
+            }
+            if candidate.digest.as_ref() == Some(&own) {
+                return Ok(Some(candidate.path.clone()));
+            }
+        }
+
+        digest = Some(own);
+    }
+
+    contents.entry(key).or_insert_with(Vec::new).push(ZipContent {
+        index: index,
+        path: path.to_string(),
+        digest: digest,
+    });
+
+    Ok(None)
+}
+
+// Name of the zip member at index to report it by, its number if even the
+// name can't be read
+fn zip_member_name<R: Read + Seek>(decoder: &mut ZipArchive<R>,
+                                   index: usize,
+                                   options: &StreamOptions)
+                                   -> String {
+    match decoder.by_index_raw(index) {
+        Ok(zf) => zip_name(&zf, options),
+        Err(_) => format!("member {}", index),
+    }
+}
+
+// Read the zip member at index through without writing it anywhere, so that
+// skip_errors can leave it out before any of it reaches the Tar archive. The
+// size limit is left to the conversion itself, which counts the data once
+fn check_zip_member<R: Read + Seek>(decoder: &mut ZipArchive<R>,
+                                    index: usize,
+                                    mtime: u64,
+                                    uid: u32,
+                                    gid: u32,
+                                    options: &StreamOptions)
+                                    -> Result<(), StreamError> {
+    let mut zf = try!(zip_member(decoder, index, options));
+
+    if let Some(member) = try!(zip_entry_header(&mut zf, mtime, uid, gid, options)) {
+        if member.header.entry_type().is_file() {
+            let mut contents = try!(zip_contents(&mut zf, options)).inner;
+            try!(std::io::copy(&mut contents, &mut std::io::sink()));
+        }
+    }
+
+    Ok(())
+}
+
+// Whether skip_errors leaves out the member that failed with e, running past
+// the size limit ends the conversion all the same
+fn is_skippable(e: &StreamError, options: &StreamOptions) -> bool {
+    match *e {
+        StreamError::TooLarge(_) => false,
+        _ => options.skip_errors.is_some(),
+    }
+}
+
+// Convert zip archive into a Tar archive written to the destination
+fn zip_into_tar<R: Read + Seek, W: Write>(reader: R,
+                                          target: W,
+                                          mtime: u64,
+                                          uid: u32,
+                                          gid: u32,
+                                          options: &StreamOptions)
+                                          -> Result<(), StreamError> {
+    let mut decoder = try!(ZipArchive::new(reader));
+    let mut tar_builder = Builder::new(target);
+    let mut contents = HashMap::new();
+
+    try!(append_zip_comment(&mut tar_builder, &decoder, options));
+
+    for i in 0..decoder.len() {
+        if let Some(ref skipped) = options.skip_errors {
+            if let Err(e) = check_zip_member(&mut decoder, i, mtime, uid, gid, options) {
+                skipped.record(zip_member_name(&mut decoder, i, options), &e);
+                continue;
+            }
+        }
+
+        // Get hold of ZipFile at particular index
+        let mut zf = try!(zip_member(&mut decoder, i, options));
+        let mut member = match try!(zip_entry_header(&mut zf, mtime, uid, gid, options)) {
+            Some(member) => member,
+            None => continue,
+        };
+
+        if member.header.entry_type().is_file() {
+            let size = zf.size();
+
+            if options.dedup_hardlinks && size > 0 {
+                drop(zf);
+
+                let duplicate = try!(find_duplicate(&mut decoder,
+                                                    i,
+                                                    &member.path,
+                                                    &mut contents,
+                                                    options));
+
+                if let Some(first) = duplicate {
+                    member.set_link(EntryType::Link, first);
+                    member.header.set_cksum();
+                    try!(append_member(&mut tar_builder, &member, 0, std::io::empty(), options));
+                    continue;
+                }
+
+                zf = try!(zip_member(&mut decoder, i, options));
+            }
+
+            try!(append_member(&mut tar_builder,
+                               &member,
+                               size,
+                               try!(zip_contents(&mut zf, options)),
+                               options));
+        } else {
+            try!(append_member(&mut tar_builder, &member, 0, std::io::empty(), options));
+        }
+    }
+
+    try!(tar_builder.finish());
+
+    Ok(())
+}
+
+// What the header of a gzip stream records about the file it was made from
+pub struct GzipHeader {
+    // Name of the original file without its directory, if it was stored
+    pub name: Option<String>,
+    // Unix timestamp of the original file, 0 if there is none
+    pub mtime: u32,
+    // Operating system gzip ran on, 3 for Unix
+    pub os: u8,
+}
+
+impl GzipHeader {
+    fn new(header: &GzHeader) -> GzipHeader {
+        GzipHeader {
+            name: header.filename().map(|name| String::from_utf8_lossy(name).into_owned()),
+            mtime: header.mtime(),
+            os: header.operating_system(),
+        }
+    }
+}
+
+// Header of the gzip stream src starts with, None for anything else
+pub fn gzip_header<R: Read>(src: R) -> Option<GzipHeader> {
+    MultiGzDecoder::new(src).header().map(GzipHeader::new)
+}
+
+// Modification time a Tar archive decompressed or copied from src should
+// get, the one gzip recorded of the original file or else that of src.
+// Archives converted member by member have none to speak of
+pub fn source_mtime(src: &File, archive_type: ArchiveType) -> Result<Option<SystemTime>, Error> {
+    if archive_type.intersects(ZIP | SEVENZIP | CPIO | AR | RAR) {
+        return Ok(None);
+    }
+
+    if archive_type.contains(GZIP) {
+        try!((&*src).seek(SeekFrom::Start(0)));
+
+        match gzip_header(src) {
+            Some(ref header) if header.mtime > 0 => {
+                return Ok(Some(UNIX_EPOCH + Duration::from_secs(header.mtime as u64)));
+            }
+            _ => (),
+        }
+    }
+
+    Ok(Some(try!(try!(src.metadata()).modified())))
+}
+
+// Modification time, uid and gid the members of a zip archive inherit from
+// the archive file itself. Windows has no owners to speak of, root it is
+#[cfg(unix)]
+fn file_owner(meta: &fs::Metadata) -> (u64, u32, u32) {
+    (meta.mtime() as u64, meta.uid(), meta.gid())
+}
+
+#[cfg(not(unix))]
+fn file_owner(meta: &fs::Metadata) -> (u64, u32, u32) {
+    let mtime = meta.modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |elapsed| elapsed.as_secs());
+
+    (mtime, 0, 0)
+}
+
+// Read at an offset without moving the shared file position
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> Result<usize, Error> {
+    file.read_at(buf, offset)
+}
+
+// seek_read moves the file position on Windows, which SharedFileReader
+// never relies on
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> Result<usize, Error> {
+    file.seek_read(buf, offset)
+}
+
+// Apply the permission bits of an extracted member, Windows only knows
+// about read-only files
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<(), Error> {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))
+}
+
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: u32) -> Result<(), Error> {
+    let mut permissions = try!(fs::metadata(path)).permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(unix)]
+fn make_symlink(link: &str, path: &Path) -> Result<(), Error> {
+    std::os::unix::fs::symlink(link, path)
+}
+
+#[cfg(windows)]
+fn make_symlink(link: &str, path: &Path) -> Result<(), Error> {
+    std::os::windows::fs::symlink_file(link, path)
+}
+
+// Independent read cursor over a file shared between threads, positional
+// reads leave the offset of the file handle alone
+struct SharedFileReader<'a> {
+    file: &'a File,
+    pos: u64,
+    len: u64,
+}
+
+impl<'a> SharedFileReader<'a> {
+    fn new(file: &'a File) -> Result<SharedFileReader<'a>, Error> {
+        let len = try!(file.metadata()).len();
+
+        Ok(SharedFileReader {
+            file: file,
+            pos: 0,
+            len: len,
+        })
+    }
+}
+
+impl<'a> Read for SharedFileReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let read = try!(read_at(self.file, buf, self.pos));
+        self.pos += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl<'a> Seek for SharedFileReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.len as i64 + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+
+        if pos < 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "seek before start of file"));
+        }
+
+        self.pos = pos as u64;
+
+        Ok(self.pos)
+    }
+}
+
+// Members larger than this aren't decompressed into memory by the zip
+// workers, the writer streams them straight from the archive instead
+const PARALLEL_BUFFER_LIMIT: u64 = 32 * 1024 * 1024;
+
+// What a zip worker hands over to the writer for a member
+enum ZipMember {
+    // Left out by the include and exclude patterns
+    Skipped,
+    // Tar header and the decompressed contents
+    Buffered(Member, Vec<u8>),
+    // Tar header of a member too large to buffer, see PARALLEL_BUFFER_LIMIT
+    Deferred(Member),
+}
+
+// Read the member at index into memory along with its tar header
+fn read_zip_entry<R: Read + Seek>(decoder: &mut ZipArchive<R>,
+                                  index: usize,
+                                  mtime: u64,
+                                  uid: u32,
+                                  gid: u32,
+                                  options: &StreamOptions)
+                                  -> Result<ZipMember, StreamError> {
+    let mut zf = try!(zip_member(decoder, index, options));
+    let member = match try!(zip_entry_header(&mut zf, mtime, uid, gid, options)) {
+        Some(member) => member,
+        None => return Ok(ZipMember::Skipped),
+    };
+    let mut data = Vec::new();
+
+    if member.header.entry_type().is_file() {
+        if zf.size() > PARALLEL_BUFFER_LIMIT {
+            return Ok(ZipMember::Deferred(member));
+        }
+
+        data.reserve(zf.size() as usize);
+        try!(try!(zip_contents(&mut zf, options)).read_to_end(&mut data));
+    }
+
+    Ok(ZipMember::Buffered(member, data))
+}
+
+// Same as zip_into_tar() but members are decompressed by options.threads
+// workers, each with its own archive handle from open. Member i goes to
+// worker i % threads, which hands it over through a single slot channel, so
+// the output keeps the zip's member order. The price is that up to two
+// decompressed members per worker are held in memory at any time, members
+// above PARALLEL_BUFFER_LIMIT are streamed by the writer itself to keep that
+// bounded
+fn zip_into_tar_parallel<R, F, W>(open: F,
+                                  target: W,
+                                  mtime: u64,
+                                  uid: u32,
+                                  gid: u32,
+                                  options: &StreamOptions)
+                                  -> Result<(), StreamError>
+    where R: Read + Seek,
+          F: Fn() -> Result<R, Error> + Sync,
+          W: Write
+{
+    let threads = options.threads;
+    let mut archive = try!(ZipArchive::new(try!(open())));
+    let count = archive.len();
+    let mut tar_builder = Builder::new(target);
+
+    try!(append_zip_comment(&mut tar_builder, &archive, options));
+
+    try!(thread::scope(|scope| -> Result<(), StreamError> {
+        let mut receivers = Vec::new();
+
+        for worker in 0..threads {
+            let (sender, receiver) = mpsc::sync_channel(1);
+            let open = &open;
+            receivers.push(receiver);
+
+            scope.spawn(move || {
+                let mut decoder = match open().map_err(StreamError::from)
+                                              .and_then(|r| Ok(try!(ZipArchive::new(r)))) {
+                    Ok(decoder) => decoder,
+                    Err(e) => {
+                        let _ = sender.send(Err(e));
+                        return;
+                    }
+                };
+
+                for index in (worker..count).step_by(threads) {
+                    let entry = read_zip_entry(&mut decoder, index, mtime, uid, gid, options);
+                    let failed = match entry {
+                        Err(ref e) => !is_skippable(e, options),
+                        Ok(_) => false,
+                    };
+
+                    // Stop once the writer hung up or this was the last word
+                    if sender.send(entry).is_err() || failed {
+                        return;
+                    }
+                }
+            });
+        }
+
+        for index in 0..count {
+            match receivers[index % threads].recv() {
+                Ok(entry) => {
+                    let entry = match (entry, options.skip_errors.as_ref()) {
+                        (Err(ref e), Some(skipped)) if is_skippable(e, options) => {
+                            skipped.record(zip_member_name(&mut archive, index, options), e);
+                            continue;
+                        }
+                        (entry, _) => try!(entry),
+                    };
+
+                    match entry {
+                        ZipMember::Skipped => (),
+                        ZipMember::Buffered(member, data) => {
+                            try!(append_member(&mut tar_builder,
+                                               &member,
+                                               data.len() as u64,
+                                               &data[..],
+                                               options));
+                        }
+                        ZipMember::Deferred(member) => {
+                            if let Some(ref skipped) = options.skip_errors {
+                                if let Err(e) = check_zip_member(&mut archive,
+                                                                 index,
+                                                                 mtime,
+                                                                 uid,
+                                                                 gid,
+                                                                 options) {
+                                    let name = zip_member_name(&mut archive, index, options);
+                                    skipped.record(name, &e);
+                                    continue;
+                                }
+                            }
+
+                            let mut zf = try!(zip_member(&mut archive, index, options));
+                            let size = zf.size();
+                            try!(append_member(&mut tar_builder,
+                                               &member,
+                                               size,
+                                               try!(zip_contents(&mut zf, options)),
+                                               options));
+                        }
+                    }
+                }
+                Err(_) => {
+                    return Err(StreamError::Io(Error::new(ErrorKind::Other,
+                                                          "zip worker thread died")))
+                }
+            }
+        }
+
+        Ok(())
+    }));
+
+    try!(tar_builder.finish());
+
+    Ok(())
+}
+
+// Convert zip archive into a Tar archive, in parallel if options ask for it
+fn convert_zip<R, F, W>(open: F,
+                        target: W,
+                        mtime: u64,
+                        uid: u32,
+                        gid: u32,
+                        options: &StreamOptions)
+                        -> Result<(), StreamError>
+    where R: Read + Seek,
+          F: Fn() -> Result<R, Error> + Sync,
+          W: Write
+{
+    // Spotting duplicates needs the members in order, so there's no threading
+    if options.threads > 1 && !options.dedup_hardlinks {
+        zip_into_tar_parallel(open, target, mtime, uid, gid, options)
+    } else {
+        zip_into_tar(try!(open()), target, mtime, uid, gid, options)
+    }
+}
+
+// Windows attribute p7zip sets when the high 16 bits hold the Unix st_mode
+const SEVENZIP_UNIX_EXTENSION: u32 = 0x8000;
+
+// Seconds between the Windows FILETIME epoch of 1601 and the Unix epoch
+const FILETIME_UNIX_OFFSET: u64 = 11644473600;
+
+// Unix permissions of a 7-Zip entry, falling back to the usual defaults for
+// archives made on Windows just like zip_mode()
+fn sevenz_mode(entry: &SevenZArchiveEntry) -> u32 {
+    let attributes = entry.windows_attributes();
+
+    if entry.has_windows_attributes && attributes & SEVENZIP_UNIX_EXTENSION != 0 {
+        attributes >> 16
+    } else if entry.is_directory() {
+        0o755
+    } else {
+        0o644
+    }
+}
+
+// Convert the FILETIME of a 7-Zip entry, 100ns ticks since 1601, into a
+// Unix timestamp. Returns None for entries without one or predating 1970
+fn sevenz_mtime(entry: &SevenZArchiveEntry) -> Option<u64> {
+    if !entry.has_last_modified_date {
+        return None;
+    }
+
+    (entry.last_modified_date().to_raw() / 10_000_000).checked_sub(FILETIME_UNIX_OFFSET)
+}
+
+// Write a single 7-Zip entry into the Tar archive, mirroring the headers
+// zip_entry_header() builds. Entries that aren't selected, and anti-items
+// marking deletions in update archives, are still read through since the
+// next entry of a solid block only starts where this one ends
+fn append_sevenz_entry<W: Write>(builder: &mut Builder<W>,
+                                 entry: &SevenZArchiveEntry,
+                                 data: &mut Read,
+                                 mtime: u64,
+                                 uid: u32,
+                                 gid: u32,
+                                 options: &StreamOptions)
+                                 -> Result<(), StreamError> {
+    let mut data = limited(VerifiedReader { inner: data }, options);
+    let name = match strip_components(entry.name(), options.strip_components) {
+        Some(ref name) if is_selected(name, options) && !entry.is_anti_item() => name.clone(),
+        _ => {
+            try!(std::io::copy(&mut data, &mut std::io::sink()));
+            return Ok(());
+        }
+    };
+    let mode = sevenz_mode(entry);
+    let mut member = try!(Member::new(name, options));
+
+    if entry.is_directory() {
+        member.header.set_entry_type(EntryType::Directory);
+        member.header.set_size(0);
+    } else if mode & S_IFMT == S_IFLNK {
+        let mut target = String::new();
+        try!(data.read_to_string(&mut target));
+
+        member.set_link(EntryType::Symlink, target);
+    } else if entry.size() <= USTAR_MAX_SIZE {
+        member.header.set_size(entry.size());
+    } else {
+        member.header.set_size(0);
+    }
+
+    member.header.set_mode(mode & 0o7777);
+    member.header.set_mtime(sevenz_mtime(entry).unwrap_or(mtime));
+    member.header.set_uid(uid);
+    member.header.set_gid(gid);
+    member.header.set_cksum();
+
+    if member.header.entry_type().is_file() {
+        try!(append_member(builder, &member, entry.size(), data, options));
+    } else {
+        try!(append_member(builder, &member, 0, std::io::empty(), options));
+    }
+
+    Ok(())
+}
+
+// Convert a 7-Zip archive into a Tar archive written to the destination.
+// Entries sharing a solid block are packed as one compressed stream, reading
+// them in random order would decompress the block again from its start for
+// every entry, so the block is decoded once front to back instead. Entries
+// thus come out in block order with empty files and directories last, and
+// carry the archive file's ownership
+fn sevenz_into_tar<R: Read + Seek, W: Write>(reader: R,
+                                             len: u64,
+                                             target: W,
+                                             mtime: u64,
+                                             uid: u32,
+                                             gid: u32,
+                                             options: &StreamOptions)
+                                             -> Result<(), StreamError> {
+    let mut archive = try!(SevenZReader::new(reader, len, Password::empty()));
+    let mut tar_builder = Builder::new(target);
+    let mut failure = None;
+
+    // The callback can only fail with the crate's own error type, so a
+    // StreamError is kept aside and stops the iteration
+    try!(archive.for_each_entries(|entry, data| {
+        match append_sevenz_entry(&mut tar_builder, entry, data, mtime, uid, gid, options) {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                failure = Some(e);
+                Ok(false)
+            }
+        }
+    }));
+
+    if let Some(e) = failure {
+        return Err(e);
+    }
+
+    try!(tar_builder.finish());
+
+    Ok(())
+}
+
+// Reads exactly size bytes of inner, running out early is an UnexpectedEof
+// rather than a short member that Builder::append() would pad over
+struct SizedReader<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> Read for SizedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+
+        let max = std::cmp::min(buf.len() as u64, self.remaining) as usize;
+        let count = try!(self.inner.read(&mut buf[..max]));
+
+        if count == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "truncated archive member"));
+        }
+
+        self.remaining -= count as u64;
+        Ok(count)
+    }
+}
+
+// Name of the member closing every cpio archive
+const CPIO_TRAILER: &'static str = "TRAILER!!!";
+
+// Member header of a newc or odc cpio archive
+struct CpioEntry {
+    name: String,
+    // Device and inode, shared by the hard links of a file
+    inode: (u64, u64, u64),
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    nlink: u64,
+    mtime: u64,
+    size: u64,
+    rdev_major: u32,
+    rdev_minor: u32,
+}
+
+// Integer field of a cpio header, hexadecimal in newc and octal in odc
+fn cpio_field(field: &[u8], radix: u32) -> Result<u64, StreamError> {
+    std::str::from_utf8(field)
+        .ok()
+        .and_then(|digits| u64::from_str_radix(digits, radix).ok())
+        .ok_or_else(|| StreamError::Corrupt(String::from("malformed cpio header")))
+}
+
+// Bytes needed to bring offset up to the 4 byte alignment of newc archives
+fn cpio_padding(offset: u64) -> u64 {
+    (4 - offset % 4) % 4
+}
+
+// Read the next member header of a cpio archive, None once the trailer is
+// reached. The padding after the name is consumed too, the one after the
+// member data is the caller's business
+fn read_cpio_header<R: Read>(reader: &mut R) -> Result<Option<(CpioEntry, bool)>, StreamError> {
+    let mut magic = [0u8; 6];
+    try!(reader.read_exact(&mut magic));
+
+    // newc fields are 8 hex digits, odc ones 6 or 11 octal digits
+    let (entry, namesize, newc) = match &magic {
+        b"070701" | b"070702" => {
+            let mut header = [0u8; 104];
+            try!(reader.read_exact(&mut header));
+
+            let field = |index: usize| cpio_field(&header[index * 8..index * 8 + 8], 16);
+            let entry = CpioEntry {
+                name: String::new(),
+                inode: (try!(field(7)), try!(field(8)), try!(field(0))),
+                mode: try!(field(1)) as u32,
+                uid: try!(field(2)),
+                gid: try!(field(3)),
+                nlink: try!(field(4)),
+                mtime: try!(field(5)),
+                size: try!(field(6)),
+                rdev_major: try!(field(9)) as u32,
+                rdev_minor: try!(field(10)) as u32,
+            };
+
+            (entry, try!(field(11)), true)
+        }
+        b"070707" => {
+            let mut header = [0u8; 70];
+            try!(reader.read_exact(&mut header));
+
+            let field = |start: usize, len: usize| cpio_field(&header[start..start + len], 8);
+            let rdev = try!(field(36, 6));
+            let entry = CpioEntry {
+                name: String::new(),
+                inode: (try!(field(0, 6)), 0, try!(field(6, 6))),
+                mode: try!(field(12, 6)) as u32,
+                uid: try!(field(18, 6)),
+                gid: try!(field(24, 6)),
+                nlink: try!(field(30, 6)),
+                mtime: try!(field(42, 11)),
+                size: try!(field(59, 11)),
+                rdev_major: (rdev >> 8) as u32,
+                rdev_minor: (rdev & 0xff) as u32,
+            };
+
+            (entry, try!(field(53, 6)), false)
+        }
+        _ => return Err(StreamError::Corrupt(String::from("bad cpio member magic"))),
+    };
+
+    // The name includes its terminating NUL
+    let mut name = vec![0u8; namesize as usize];
+    try!(reader.read_exact(&mut name));
+
+    if newc {
+        let mut padding = [0u8; 3];
+        let len = cpio_padding(110 + namesize) as usize;
+        try!(reader.read_exact(&mut padding[..len]));
+    }
+
+    while name.last() == Some(&0) {
+        name.pop();
+    }
+
+    let name = String::from_utf8_lossy(&name).into_owned();
+
+    if name == CPIO_TRAILER {
+        return Ok(None);
+    }
+
+    Ok(Some((CpioEntry { name: name, ..entry }, newc)))
+}
+
+// Consume NUL bytes up to the next archive member, false if the input ends
+// with them instead
+fn skip_nul_padding<R: BufRead>(reader: &mut R) -> Result<bool, Error> {
+    loop {
+        let (zeros, available) = {
+            let buf = try!(reader.fill_buf());
+            (buf.iter().take_while(|&&b| b == 0).count(), buf.len())
+        };
+
+        if available == 0 {
+            return Ok(false);
+        }
+
+        reader.consume(zeros);
+
+        if zeros < available {
+            return Ok(true);
+        }
+    }
+}
+
+// Build the tar header for a cpio member, name already stripped. Unlike zip
+// cpio headers carry everything a tar header needs
+fn cpio_entry_header(entry: &CpioEntry,
+                     name: &str,
+                     entry_type: EntryType,
+                     options: &StreamOptions)
+                     -> Result<Member, StreamError> {
+    let mut member = try!(Member::new(name.to_string(), options));
+
+    member.header.set_entry_type(entry_type);
+
+    if entry_type.is_file() && entry.size <= USTAR_MAX_SIZE {
+        member.header.set_size(entry.size);
+    } else {
+        member.header.set_size(0);
+    }
+
+    if entry_type == EntryType::Char || entry_type == EntryType::Block {
+        try!(member.header.set_device_major(entry.rdev_major));
+        try!(member.header.set_device_minor(entry.rdev_minor));
+    }
+
+    member.header.set_mode(entry.mode & 0o7777);
+    member.header.set_mtime(entry.mtime);
+    member.header.set_uid(entry.uid as u32);
+    member.header.set_gid(entry.gid as u32);
+    member.header.set_cksum();
+
+    Ok(member)
+}
+
+// Point a tar header at the member it's a hard link of, which went through
+// the same transforms
+fn set_hard_link(member: &mut Member, target: &str, options: &StreamOptions) {
+    member.set_link(EntryType::Link, transform_name(target.to_string(), options));
+    member.header.set_cksum();
+}
+
+// Convert a newc or odc cpio archive into a Tar archive written to the
+// destination. Hard links become tar links to the first member written for
+// the inode. newc only stores the data with the last link, so earlier empty
+// links are held back until it turns up. Archives concatenated one after
+// another, as initramfs images with early microcode are, all get converted.
+// Sockets have no tar counterpart and are left out
+fn cpio_into_tar<R: Read, W: Write>(reader: R,
+                                    target: W,
+                                    options: &StreamOptions)
+                                    -> Result<(), StreamError> {
+    let mut reader = BufReader::new(reader);
+    let mut tar_builder = Builder::new(target);
+    let mut links: HashMap<(u64, u64, u64), String> = HashMap::new();
+    let mut pending: Vec<(CpioEntry, String)> = Vec::new();
+
+    loop {
+        let (entry, newc) = match try!(read_cpio_header(&mut reader)) {
+            Some(header) => header,
+            None if try!(skip_nul_padding(&mut reader)) => continue,
+            None => break,
+        };
+
+        let name = match strip_components(&entry.name, options.strip_components) {
+            Some(ref name) if is_selected(name, options) => Some(name.clone()),
+            _ => None,
+        };
+        let entry_type = match entry.mode & S_IFMT {
+            S_IFDIR => Some(EntryType::Directory),
+            S_IFLNK => Some(EntryType::Symlink),
+            S_IFCHR => Some(EntryType::Char),
+            S_IFBLK => Some(EntryType::Block),
+            S_IFIFO => Some(EntryType::Fifo),
+            S_IFSOCK => None,
+            _ => Some(EntryType::Regular),
+        };
+        let padding = if newc { cpio_padding(entry.size) } else { 0 };
+        let size = entry.size;
+        let mut data = SizedReader {
+            inner: &mut reader,
+            remaining: size,
+        };
+
+        match (name, entry_type) {
+            (Some(name), Some(EntryType::Symlink)) => {
+                let mut target = String::new();
+                try!(data.read_to_string(&mut target));
+
+                let mut member = try!(cpio_entry_header(&entry,
+                                                        &name,
+                                                        EntryType::Symlink,
+                                                        options));
+
+                member.set_link(EntryType::Symlink, target);
+                member.header.set_cksum();
+                try!(append_member(&mut tar_builder, &member, 0, std::io::empty(), options));
+            }
+            (Some(name), Some(EntryType::Regular)) if entry.nlink > 1 => {
+                let mut member = try!(cpio_entry_header(&entry,
+                                                        &name,
+                                                        EntryType::Regular,
+                                                        options));
+
+                if let Some(target) = links.get(&entry.inode) {
+                    set_hard_link(&mut member, target, options);
+                    try!(append_member(&mut tar_builder, &member, 0, std::io::empty(), options));
+                } else if size == 0 && newc {
+                    // The data may still come with a later link
+                    pending.push((entry, name));
+                    continue;
+                } else {
+                    try!(append_member(&mut tar_builder, &member, size, &mut data, options));
+                }
+
+                links.entry(entry.inode).or_insert(name.clone());
+
+                // Links held back come right after the member with the data
+                let (linked, rest): (Vec<_>, Vec<_>) = pending.into_iter()
+                    .partition(|&(ref held, _)| held.inode == entry.inode);
+                pending = rest;
+
+                for (held, held_name) in linked {
+                    let mut link = try!(cpio_entry_header(&held,
+                                                          &held_name,
+                                                          EntryType::Regular,
+                                                          options));
+
+                    set_hard_link(&mut link, &name, options);
+                    try!(append_member(&mut tar_builder, &link, 0, std::io::empty(), options));
+                }
+            }
+            (Some(name), Some(entry_type)) => {
+                let member = try!(cpio_entry_header(&entry, &name, entry_type, options));
+
+                if entry_type.is_file() {
+                    try!(append_member(&mut tar_builder, &member, size, &mut data, options));
+                } else {
+                    try!(append_member(&mut tar_builder, &member, 0, std::io::empty(), options));
+                }
+            }
+            _ => (),
+        }
+
+        try!(std::io::copy(&mut data, &mut std::io::sink()));
+
+        let mut skip = [0u8; 3];
+        try!(reader.read_exact(&mut skip[..padding as usize]));
+    }
+
+    // Hard linked files that were empty after all, the first one gets
+    // written out and the rest linked to it
+    for (entry, name) in pending {
+        let mut member = try!(cpio_entry_header(&entry, &name, EntryType::Regular, options));
+
+        if let Some(target) = links.get(&entry.inode) {
+            set_hard_link(&mut member, target, options);
+        }
+
+        try!(append_member(&mut tar_builder, &member, 0, std::io::empty(), options));
+        links.entry(entry.inode).or_insert(name);
+    }
+
+    try!(tar_builder.finish());
+
+    Ok(())
+}
+
+// Convert an ar archive, a static library or Debian package, into a Tar
+// archive written to the destination. ar only records plain files, the
+// symbol and long name tables are left out. With options.recurse members
+// that are Tar archives by their magic or name, as the control.tar.gz and
+// data.tar.xz of a package are, have their contents copied in their place
+fn ar_into_tar<R: Read, W: Write>(reader: R,
+                                  target: W,
+                                  options: &StreamOptions)
+                                  -> Result<(), StreamError> {
+    let mut archive = ar::Archive::new(reader);
+    let mut tar_builder = Builder::new(target);
+
+    while let Some(entry) = archive.next_entry() {
+        let entry = try!(entry);
+        let header = entry.header().clone();
+        let member = String::from_utf8_lossy(header.identifier()).into_owned();
+        let mut data = SizedReader {
+            inner: entry,
+            remaining: header.size(),
+        };
+
+        if options.recurse {
+            let (mut typ, payload) = try!(peek_archive_type(&mut data));
+            set_filename_flags(&member, &mut typ);
+
+            if typ.contains(TAR) {
+                if options.verbose() {
+                    eprintln!("Tar member {}", member);
+                }
+
+                let (_, payload) = try!(unwrap_payload(payload, typ, options));
+                try!(copy_tar_members(payload, &mut tar_builder, options));
+                continue;
+            }
+
+            try!(append_ar_member(&mut tar_builder, &header, &member, payload, options));
+        } else {
+            try!(append_ar_member(&mut tar_builder, &header, &member, data, options));
+        }
+    }
+
+    try!(tar_builder.finish());
+
+    Ok(())
+}
+
+// Write a single ar member into the Tar archive as a regular file, ar
+// headers carry the mode, owners and modification time of their members
+fn append_ar_member<W: Write, R: Read>(builder: &mut Builder<W>,
+                                       header: &ar::Header,
+                                       member: &str,
+                                       data: R,
+                                       options: &StreamOptions)
+                                       -> Result<(), StreamError> {
+    let name = match strip_components(member, options.strip_components) {
+        Some(ref name) if is_selected(name, options) => name.clone(),
+        _ => return Ok(()),
+    };
+    let mut member = try!(Member::new(name, options));
+
+    member.header.set_entry_type(EntryType::Regular);
+    member.header.set_size(if header.size() <= USTAR_MAX_SIZE { header.size() } else { 0 });
+    member.header.set_mode(header.mode() & 0o7777);
+    member.header.set_mtime(header.mtime());
+    member.header.set_uid(header.uid());
+    member.header.set_gid(header.gid());
+    member.header.set_cksum();
+
+    try!(append_member(builder, &member, header.size(), data, options));
+
+    Ok(())
+}
+
+// What's needed of a RAR member header once its content has been read, the
+// name already stripped
+#[cfg(feature = "rar")]
+struct RarEntry {
+    name: String,
+    directory: bool,
+    attributes: u32,
+    time: u32,
+}
+
+// Build the tar header for a member of a RAR archive along the lines of
+// zip_entry_header(), data is the member's content. RAR archives made on
+// Unix carry the full st_mode in the attributes, Windows ones DOS attributes
+// which get the usual default modes
+#[cfg(feature = "rar")]
+fn rar_entry_header(entry: &RarEntry,
+                    data: &[u8],
+                    mtime: u64,
+                    uid: u32,
+                    gid: u32,
+                    options: &StreamOptions)
+                    -> Result<Member, StreamError> {
+    let mode = match entry.attributes & S_IFMT {
+        0 if entry.directory => 0o755,
+        0 => 0o644,
+        _ => entry.attributes,
+    };
+    let mut member = try!(Member::new(entry.name.clone(), options));
+
+    if entry.directory {
+        member.header.set_entry_type(EntryType::Directory);
+        member.header.set_size(0);
+    } else if mode & S_IFMT == S_IFLNK {
+        member.set_link(EntryType::Symlink, String::from_utf8_lossy(data).into_owned());
+    } else if data.len() as u64 <= USTAR_MAX_SIZE {
+        member.header.set_size(data.len() as u64);
+    } else {
+        member.header.set_size(0);
+    }
+
+    // The file time is a DOS timestamp just like in zips
+    let time = zip::DateTime::from_msdos((entry.time >> 16) as u16, entry.time as u16);
+    let time = match time.to_time() {
+        Ok(time) if time.unix_timestamp() >= 0 => time.unix_timestamp() as u64,
+        _ => mtime,
+    };
+
+    member.header.set_mode(mode & 0o7777);
+    member.header.set_mtime(time);
+    member.header.set_uid(uid);
+    member.header.set_gid(gid);
+    member.header.set_cksum();
+
+    Ok(member)
+}
+
+// Convert the RAR archive at path into a Tar archive written to dst. The
+// unrar library only reads archives from the file system and hands over
+// whole members, so every member is held in memory while it's written out.
+// Members carry the archive file's ownership
+#[cfg(feature = "rar")]
+pub fn stream_rar<P: AsRef<Path>, W: Write>(path: P,
+                                            dst: W,
+                                            options: &StreamOptions)
+                                            -> Result<(), StreamError> {
+    let path = path.as_ref();
+
+    try!(check_no_head(RAR, options));
+
+    let (mtime, uid, gid) = file_owner(&try!(fs::metadata(path)));
+    let mut archive = try!(unrar::Archive::new(path).open_for_processing());
+
+    if archive.has_encrypted_headers() {
+        return Err(StreamError::Encrypted(path.to_string_lossy().into_owned()));
+    }
+
+    if options.verbose() {
+        eprintln!("RAR file");
+    }
+
+    let mut dst = try!(open_output(dst, options));
+
+    {
+        let mut tar_builder = Builder::new(&mut dst);
+
+        while let Some(entry) = try!(archive.read_header()) {
+            let member = entry.entry().filename.to_string_lossy().into_owned();
+            let name = match strip_components(&member, options.strip_components) {
+                Some(ref name) if is_selected(name, options) => name.clone(),
+                _ => {
+                    archive = try!(entry.skip());
+                    continue;
+                }
+            };
+
+            if entry.entry().is_encrypted() {
+                return Err(StreamError::Encrypted(member));
+            }
+
+            let rar_entry = RarEntry {
+                name: name,
+                directory: entry.entry().is_directory(),
+                attributes: entry.entry().file_attr,
+                time: entry.entry().file_time,
+            };
+            let data = if rar_entry.directory {
+                archive = try!(entry.skip());
+                Vec::new()
+            } else {
+                let (data, rest) = try!(entry.read());
+                archive = rest;
+
+                // unrar hands over whole members, so they're counted once read
+                if let Some(limit) = options.max_size.as_ref() {
+                    try!(limit.consume(data.len() as u64));
+                }
+
+                data
+            };
+
+            let member = try!(rar_entry_header(&rar_entry, &data, mtime, uid, gid, options));
+
+            if member.header.entry_type().is_file() {
+                try!(append_member(&mut tar_builder,
+                                   &member,
+                                   data.len() as u64,
+                                   &data[..],
+                                   options));
+            } else {
+                try!(append_member(&mut tar_builder, &member, 0, std::io::empty(), options));
+            }
+        }
+
+        try!(tar_builder.finish());
+    }
+
+    close_output(dst)
+}
+
+// RAR support is left out of builds without the rar feature
+#[cfg(not(feature = "rar"))]
+pub fn stream_rar<P: AsRef<Path>, W: Write>(_path: P,
+                                            _dst: W,
+                                            _options: &StreamOptions)
+                                            -> Result<(), StreamError> {
+    Err(StreamError::UnsupportedType(RAR))
+}
+
+// Returns true if the archive type has a compression layer to peel off
+fn is_compressed(typ: ArchiveType) -> bool {
+    typ.intersects(GZIP | BZIP2 | XZ | ZSTD | LZ4 | LZMA | LZIP | LZOP | COMPRESS | BROTLI)
+}
+
+// Returns true if the archive type is handled by the zip-to-tar conversion,
+// compression layers take precedence over it
+fn is_zip(typ: ArchiveType) -> bool {
+    typ.contains(ZIP) && !is_compressed(typ)
+}
+
+// Returns true if the archive type is handled by the 7-Zip-to-tar conversion
+fn is_sevenzip(typ: ArchiveType) -> bool {
+    typ.contains(SEVENZIP) && !is_compressed(typ)
+}
+
+// Decoder for bzip2 streams, libbzip2's unless built with pure-rust-bzip2
+#[cfg(not(feature = "pure-rust-bzip2"))]
+fn bzip2_decoder<'a, R: Read + 'a>(input: R) -> Box<Read + 'a> {
+    Box::new(MultiBzDecoder::new(input))
+}
+
+#[cfg(feature = "pure-rust-bzip2")]
+fn bzip2_decoder<'a, R: Read + 'a>(input: R) -> Box<Read + 'a> {
+    Box::new(bzip2_pure::read::MultiBzDecoder::new(input))
+}
+
+// Decoder for an xz stream, liblzma's unless built with pure-rust-xz
+#[cfg(not(feature = "pure-rust-xz"))]
+fn xz_decoder<'a, R: Read + 'a>(input: R) -> Box<Read + 'a> {
+    Box::new(XzDecoder::new(input))
+}
+
+#[cfg(feature = "pure-rust-xz")]
+fn xz_decoder<'a, R: Read + 'a>(input: R) -> Box<Read + 'a> {
+    Box::new(lzma_rust2::XzReader::new(input, false))
+}
+
+// Bytes of the xz stream header and of the stream footer
+#[cfg(feature = "parallel-xz")]
+const XZ_HEADER_SIZE: u64 = 12;
+#[cfg(feature = "parallel-xz")]
+const XZ_FOOTER_SIZE: u64 = 12;
+
+// Number of blocks of the xz file input, read from the index in front of the
+// stream footer. None unless input is exactly one stream, concatenated streams
+// and stream padding are only found by decoding from the start
+#[cfg(feature = "parallel-xz")]
+fn xz_block_count<R: Read + Seek>(input: &mut R) -> Result<Option<u64>, Error> {
+    let size = try!(input.seek(SeekFrom::End(0)));
+    let mut footer = [0u8; 12];
+
+    if size < XZ_HEADER_SIZE + XZ_FOOTER_SIZE + 8 {
+        return Ok(None);
+    }
+
+    try!(input.seek(SeekFrom::End(-(XZ_FOOTER_SIZE as i64))));
+    try!(input.read_exact(&mut footer));
+
+    // The backward size counts the index in 4 byte units, less one
+    let backward = footer[4..8].iter().rev().fold(0, |n, &b| n << 8 | b as u64);
+    let index_size = (backward + 1) * 4;
+
+    if &footer[10..] != b"YZ" || index_size > size - XZ_HEADER_SIZE - XZ_FOOTER_SIZE {
+        return Ok(None);
+    }
+
+    let mut index = vec![0u8; index_size as usize];
+
+    try!(input.seek(SeekFrom::End(-((XZ_FOOTER_SIZE + index_size) as i64))));
+    try!(input.read_exact(&mut index));
+
+    // The record count and both sizes of every block are variable length
+    // integers of 7 bits a byte, the low ones first
+    let mut bytes = index[1..].iter();
+    let mut number = move || {
+        let mut n = 0u64;
+
+        for (i, &b) in bytes.by_ref().take(9).enumerate() {
+            n |= ((b & 0x7f) as u64) << (7 * i);
+
+            if b & 0x80 == 0 {
+                return Some(n);
+            }
+        }
+
+        None
+    };
+
+    let count = match (index[0], number()) {
+        (0, Some(count)) => count,
+        _ => return Ok(None),
+    };
+    let mut blocks = 0u64;
+
+    // Blocks are padded to a multiple of 4 bytes
+    for _ in 0..count {
+        match (number(), number()) {
+            (Some(unpadded), Some(_)) => blocks = blocks.saturating_add((unpadded + 3) & !3),
+            _ => return Ok(None),
+        }
+    }
+
+    match XZ_HEADER_SIZE + blocks.min(size) + index_size + XZ_FOOTER_SIZE == size {
+        true => Ok(Some(count)),
+        false => Ok(None),
+    }
+}
+
+// Same as unwrap_payload() for an xz file whose blocks are decompressed on
+// options.threads threads side by side, like xz -T0 does. The blocks are
+// located by the index, so input has to be seekable. None if there's nothing
+// to split up, a single block or anything but exactly one stream
+#[cfg(feature = "parallel-xz")]
+fn unwrap_xz_blocks<'a, R>(mut input: R,
+                           typ: ArchiveType,
+                           options: &'a StreamOptions)
+                           -> Result<Option<(ArchiveType, Box<Read + 'a>)>, StreamError>
+    where R: Read + Seek + 'a
+{
+    let blocks = match try!(xz_block_count(&mut input)) {
+        Some(blocks) if blocks > 1 => blocks,
+        _ => return Ok(None),
+    };
+
+    try!(input.seek(SeekFrom::Start(0)));
+
+    if options.verbose() {
+        eprintln!("XZ file of {} blocks, decompressed in {} threads", blocks, options.threads);
+    }
+
+    let consumed = Rc::new(Cell::new(0));
+    let input = CountingReader {
+        inner: input,
+        count: consumed.clone(),
+    };
+    let decoder = try!(lzma_rust2::XzReaderMt::new(input, false, options.threads as u32));
+
+    peel_payload(Box::new(VerifiedReader { inner: decoder }), consumed, typ, options).map(Some)
+}
+
+// Builds without parallel-xz decompress every xz file on one thread
+#[cfg(not(feature = "parallel-xz"))]
+fn unwrap_xz_blocks<'a, R>(_input: R,
+                           _typ: ArchiveType,
+                           _options: &'a StreamOptions)
+                           -> Result<Option<(ArchiveType, Box<Read + 'a>)>, StreamError>
+    where R: Read + Seek + 'a
+{
+    Ok(None)
+}
+
+// Wrap input in the decoder for the outermost compression layer of typ, the
+// one place knowing which decoder goes with which compression. Anything
+// that isn't compressed is UnsupportedType
+pub fn make_decoder<'a, R: Read + 'a>(mut input: R,
+                                      typ: ArchiveType,
+                                      verbose: bool)
+                                      -> Result<Box<Read + 'a>, StreamError> {
+    if typ.contains(GZIP) {
+        if verbose {
+            eprintln!("GZip file");
+        }
+
+        // Concatenated members decompress into one stream, as with gzip -dc
+        let decoder = MultiGzDecoder::new(input);
+
+        if let (true, Some(header)) = (verbose, decoder.header()) {
+            let header = GzipHeader::new(header);
+
+            eprintln!("Original name {}, mtime {}, OS {}",
+                      header.name.as_ref().map_or("unknown", |name| &name[..]),
+                      header.mtime,
+                      header.os);
+        }
+
+        Ok(Box::new(decoder))
+    } else if typ.contains(BZIP2) {
+        if verbose {
+            eprintln!("BZip2 file");
+        }
+
+        // pbzip2 and friends write several concatenated streams
+        Ok(bzip2_decoder(input))
+    } else if typ.contains(XZ) {
+        if verbose {
+            eprintln!("XZ file");
+        }
+
+        Ok(xz_decoder(input))
+    } else if typ.contains(LZMA) {
+        if verbose {
+            eprintln!("LZMA file");
+        }
+
+        // The legacy .lzma container, xz's predecessor
+        let stream = try!(Stream::new_lzma_decoder(std::u64::MAX).map_err(Error::from));
+        Ok(Box::new(XzDecoder::new_stream(input, stream)))
+    } else if typ.contains(LZIP) {
+        if verbose {
+            eprintln!("Lzip file");
+        }
+
+        Ok(Box::new(LzipDecoder::new(input)))
+    } else if typ.contains(LZOP) {
+        if verbose {
+            eprintln!("Lzop file");
+        }
+
+        Ok(Box::new(try!(LzopDecoder::new(input))))
+    } else if typ.contains(COMPRESS) {
+        if verbose {
+            eprintln!("Compress file");
+        }
+
+        Ok(Box::new(try!(LzwDecoder::new(input))))
+    } else if typ.contains(ZSTD) {
+        if verbose {
+            eprintln!("Zstandard file");
+        }
+
+        Ok(Box::new(try!(ZstdDecoder::new(input))))
+    } else if typ.contains(BROTLI) {
+        if verbose {
+            eprintln!("Brotli file, going by the .br extension or --type as brotli has no \
+                       magic bytes");
+        }
+
+        Ok(Box::new(BrotliDecoder::new(input, 4096)))
+    } else if typ.contains(LZ4) {
+        let mut magic = [0u8; 4];
+
+        // Check for the legacy frame format first and put the peeked bytes
+        // back in front to let the regular frame decoder handle everything else
+        let peeked = try!(read_head(&mut input, &mut magic));
+
+        let legacy = peeked == magic.len() &&
+                     ((magic[0] as u32) | (magic[1] as u32) << 8 | (magic[2] as u32) << 16 |
+                      (magic[3] as u32) << 24) == LZ4_LEGACY_MAGIC;
+
+        if legacy {
+            if verbose {
+                eprintln!("LZ4 legacy file");
+            }
+
+            Ok(Box::new(Lz4LegacyDecoder::new(input)))
+        } else {
+            if verbose {
+                eprintln!("LZ4 file");
+            }
+
+            let head = Cursor::new(magic[..peeked].to_vec());
+            Ok(Box::new(Lz4FrameDecoder { inner: Some(try!(Lz4Decoder::new(head.chain(input)))) }))
+        }
+    } else {
+        Err(StreamError::UnsupportedType(typ))
+    }
+}
+
+// Peel off the compression layer of typ, if any, and sniff the payload again,
+// up to options.decode_layers times. Old v7 tars have no magic, in which case
+// the outer TAR flag is kept
+fn unwrap_payload<'a, R: Read + 'a>(input: R,
+                                    typ: ArchiveType,
+                                    options: &'a StreamOptions)
+                                    -> Result<(ArchiveType, Box<Read + 'a>), StreamError> {
+    if !is_compressed(typ) {
+        let (inner, payload) = try!(peek_archive_type(input));
+
+        return Ok((inner | (typ & TAR), Box::new(payload)));
+    }
+
+    let consumed = Rc::new(Cell::new(0));
+    let input = CountingReader {
+        inner: input,
+        count: consumed.clone(),
+    };
+    let decoder = try!(make_decoder(input, typ, options.verbose()));
+
+    peel_payload(Box::new(VerifiedReader { inner: decoder }), consumed, typ, options)
+}
+
+// Decode the compression found inside payload, the outermost layer of typ
+// decoded from consumed bytes of input, and hold the result to the limits
+fn peel_payload<'a>(mut payload: Box<Read + 'a>,
+                    consumed: Rc<Cell<u64>>,
+                    typ: ArchiveType,
+                    options: &'a StreamOptions)
+                    -> Result<(ArchiveType, Box<Read + 'a>), StreamError> {
+    let mut layers = 1;
+
+    // Peel off compression found inside, the likes of .tar.gz.gz, as long as
+    // options allow for more layers
+    let inner = loop {
+        let (inner, peeked) = try!(peek_archive_type(payload));
+
+        if !is_compressed(inner) || layers >= options.decode_layers {
+            payload = Box::new(peeked);
+            break inner;
+        }
+
+        let decoder = try!(make_decoder(peeked, inner, options.verbose()));
+
+        payload = Box::new(VerifiedReader { inner: decoder });
+        layers += 1;
+    };
+
+    // The limits hold the innermost payload against what was read from src
+    let decoder = RatioReader {
+        inner: payload,
+        input: consumed,
+        output: 0,
+        limit: options.ratio_limit,
+    };
+
+    Ok((inner | (typ & TAR), Box::new(limited(decoder, options))))
+}
+
+// Range of compression levels the codec of typ accepts along with the level
+// used by default, None if typ can't be recompressed into
+pub fn compression_levels(typ: ArchiveType) -> Option<(u32, u32, u32)> {
+    match typ {
+        GZIP => Some((0, 9, 6)),
+        BZIP2 => Some((1, 9, 6)),
+        XZ => Some((0, 9, 6)),
+        ZSTD => Some((1, 22, 3)),
+        _ => None,
+    }
+}
+
+// Writer compressing everything into the destination, or passing it through
+enum Recompressor<W: Write> {
+    Plain(W),
+    Gzip(GzEncoder<W>),
+    Bzip2(BzEncoder<W>),
+    Xz(XzEncoder<W>),
+    Zstd(ZstdEncoder<'static, W>),
+}
+
+impl<W: Write> Recompressor<W> {
+    fn new(dst: W,
+           typ: ArchiveType,
+           level: Option<u32>,
+           verbose: bool)
+           -> Result<Recompressor<W>, StreamError> {
+        if typ == INVALID {
+            return Ok(Recompressor::Plain(dst));
+        }
+
+        let (min, max, default) = match compression_levels(typ) {
+            Some(levels) => levels,
+            None => return Err(StreamError::UnsupportedType(typ)),
+        };
+        let level = level.unwrap_or(default);
+
+        if level < min || level > max {
+            return Err(StreamError::Io(Error::new(ErrorKind::InvalidInput,
+                                                  format!("compression level {} is out of \
+                                                           range {}-{} for {}",
+                                                          level,
+                                                          min,
+                                                          max,
+                                                          typ))));
+        }
+
+        let encoder = match typ {
+            GZIP => Recompressor::Gzip(GzEncoder::new(dst, flate2::Compression::new(level))),
+            BZIP2 => Recompressor::Bzip2(BzEncoder::new(dst, bzip2::Compression::new(level))),
+            XZ => Recompressor::Xz(XzEncoder::new(dst, level)),
+            _ => Recompressor::Zstd(try!(ZstdEncoder::new(dst, level as i32))),
+        };
+
+        if verbose {
+            eprintln!("Compressing output as {} at level {}", typ, level);
+        }
+
+        Ok(encoder)
+    }
+
+    // Write out the end of the compressed stream
+    fn finish(self) -> Result<W, Error> {
+        match self {
+            Recompressor::Plain(w) => Ok(w),
+            Recompressor::Gzip(w) => w.finish(),
+            Recompressor::Bzip2(w) => w.finish(),
+            Recompressor::Xz(w) => w.finish(),
+            Recompressor::Zstd(w) => w.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Recompressor<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match *self {
+            Recompressor::Plain(ref mut w) => w.write(buf),
+            Recompressor::Gzip(ref mut w) => w.write(buf),
+            Recompressor::Bzip2(ref mut w) => w.write(buf),
+            Recompressor::Xz(ref mut w) => w.write(buf),
+            Recompressor::Zstd(ref mut w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match *self {
+            Recompressor::Plain(ref mut w) => w.flush(),
+            Recompressor::Gzip(ref mut w) => w.flush(),
+            Recompressor::Bzip2(ref mut w) => w.flush(),
+            Recompressor::Xz(ref mut w) => w.flush(),
+            Recompressor::Zstd(ref mut w) => w.flush(),
+        }
+    }
+}
+
+// Output set up by open_output()
+type Output<'a, W> = BufWriter<ChecksumWriter<'a, Recompressor<W>>>;
+
+// Set up the buffered, optionally hashed and compressing, output for dst
+fn open_output<W: Write>(dst: W, options: &StreamOptions) -> Result<Output<W>, StreamError> {
+    let encoder = try!(Recompressor::new(dst, options.recompress, options.level, options.verbose()));
+
+    // Tar headers of zip entries are small writes, so batch them up
+    Ok(BufWriter::new(ChecksumWriter {
+        inner: encoder,
+        checksum: options.checksum.as_ref(),
+    }))
+}
+
+// Flush the output set up by open_output() and end the compressed stream
+fn close_output<W: Write>(output: Output<W>) -> Result<(), StreamError> {
+    let hashed = try!(output.into_inner().map_err(|e| e.into_error()));
+    try!(try!(hashed.inner.finish()).flush());
+
+    Ok(())
+}
+
+// Records in the body of a PAX extended header, up to the first malformed one
+fn pax_records(data: &[u8]) -> Vec<&[u8]> {
+    let mut records = Vec::new();
+    let mut rest = data;
+
+    // Every record is "<length> <key>=<value>\n", the length counting itself
+    while let Some(space) = rest.iter().position(|&b| b == b' ') {
+        let len = match std::str::from_utf8(&rest[..space]).ok().and_then(|l| l.parse().ok()) {
+            Some(len) if len > space + 1 && len <= rest.len() => len,
+            _ => break,
+        };
+
+        records.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+
+    records
+}
+
+// Value of a record returned by pax_records() if it's the one of key
+fn pax_value(record: &[u8], key: &str) -> Option<String> {
+    let space = record.iter().position(|&b| b == b' ').unwrap_or(0);
+    let body = String::from_utf8_lossy(&record[space + 1..record.len() - 1]);
+
+    match body.find('=') {
+        Some(end) if &body[..end] == key => Some(body[end + 1..].to_string()),
+        _ => None,
+    }
+}
+
+// Value of the path record in the body of a PAX extended header, if any
+fn pax_path(data: &[u8]) -> Option<String> {
+    pax_records(data).into_iter().filter_map(|record| pax_value(record, "path")).next()
+}
+
+// Body of a PAX extended header with its path records changed to path
+fn pax_renamed(data: &[u8], path: &str) -> Vec<u8> {
+    pax_records(data)
+        .into_iter()
+        .flat_map(|record| match pax_value(record, "path") {
+            Some(_) => pax_record("path", path).into_bytes(),
+            None => record.to_vec(),
+        })
+        .collect()
+}
+
+// Copy the members of a Tar archive passing the include and exclude patterns.
+// Entries are copied as they are, the GNU long name and PAX entries
+// describing a member are held back until it's known whether the member
+// itself is kept
+fn filter_tar<R: Read, W: Write>(reader: R,
+                                 dst: W,
+                                 options: &StreamOptions)
+                                 -> Result<(), StreamError> {
+    let mut builder = Builder::new(dst);
+
+    try!(copy_tar_members(reader, &mut builder, options));
+    try!(builder.finish());
+
+    Ok(())
+}
+
+// Append the selected members of a Tar archive to builder, see filter_tar()
+fn copy_tar_members<R: Read, W: Write>(reader: R,
+                                       builder: &mut Builder<W>,
+                                       options: &StreamOptions)
+                                       -> Result<(), StreamError> {
+    let mut archive = tar::Archive::new(reader);
+    let mut pending: Vec<(Header, Vec<u8>)> = Vec::new();
+    let mut long_name = None;
+    let mut long_path = None;
+
+    for entry in try!(archive.entries()).raw(true) {
+        let mut entry = try!(entry);
+        let mut header = entry.header().clone();
+        let kind = header.entry_type();
+
+        if kind.is_gnu_longname() || kind.is_gnu_longlink() || kind.is_pax_local_extensions() {
+            let mut data = Vec::new();
+            try!(entry.read_to_end(&mut data));
+
+            if kind.is_gnu_longname() {
+                long_name = Some(String::from_utf8_lossy(&data)
+                    .trim_right_matches('\0')
+                    .to_string());
+            } else if kind.is_pax_local_extensions() {
+                long_path = pax_path(&data);
+            }
+
+            pending.push((header, data));
+            continue;
+        }
+
+        // PAX records override GNU long names, which override the header
+        let name = match long_path.take().or(long_name.take()) {
+            Some(name) => name,
+            None => try!(header.path()).to_string_lossy().into_owned(),
+        };
+
+        if kind.is_pax_global_extensions() || is_selected(&name, options) {
+            for (pending_header, data) in pending.drain(..) {
+                try!(builder.append(&pending_header, &data[..]));
+            }
+
+            if is_rewriting_headers(options) && !kind.is_pax_global_extensions() {
+                override_header(&mut header, options);
+            }
+
+            match options.manifest {
+                Some(ref manifest) if !kind.is_pax_global_extensions() => {
+                    let checksum = manifest.checksum();
+
+                    try!(builder.append(&header,
+                                        ChecksumReader {
+                                            inner: &mut entry,
+                                            checksum: checksum.as_ref(),
+                                        }));
+                    manifest.record(&name,
+                                    try!(header.size()),
+                                    header.mode().unwrap_or(0),
+                                    checksum);
+                }
+                _ => try!(builder.append(&header, &mut entry)),
+            }
+        } else {
+            pending.clear();
+        }
+    }
+
+    Ok(())
+}
+
+// Stream src archive into dst, producing a Tar archive or the decompressed
+// payload. An archive type of INVALID sniffs the type from the magic bytes
+pub fn stream<R: Read, W: Write>(src: R,
+                                 dst: W,
+                                 archive_type: ArchiveType,
+                                 block_size: usize)
+                                 -> Result<(), StreamError> {
+    let options = StreamOptions { block_size: block_size, ..Default::default() };
+
+    stream_with_options(src, dst, archive_type, &options)
+}
+
+// Convert the payload of type inner that unwrap_payload() came up with
+fn stream_payload<R: Read, W: Write>(inner: ArchiveType,
+                                     payload: R,
+                                     dst: &mut W,
+                                     buffer: &mut [u8],
+                                     options: &StreamOptions)
+                                     -> Result<(), StreamError> {
+    if !inner.contains(CPIO) && !is_parsing_tar(options) {
+        return decode_file_into(buffer, dst, payload, options).map(|_| ());
+    }
+
+    try!(check_no_head(inner, options));
+
+    if inner.contains(CPIO) {
+        // Compressed cpio archives, the likes of initramfs images and RPM
+        // payloads, get converted as well
+        cpio_into_tar(payload, dst, options)
+    } else if inner.contains(TAR) {
+        // Picking or recording members means parsing the Tar archive instead of
+        // copying it
+        filter_tar(payload, dst, options)
+    } else {
+        Err(StreamError::UnsupportedType(inner))
+    }
+}
+
+// Same as stream() with explicit options
+pub fn stream_with_options<R: Read, W: Write>(src: R,
+                                              dst: W,
+                                              archive_type: ArchiveType,
+                                              options: &StreamOptions)
+                                              -> Result<(), StreamError> {
+    let src = ProgressReader::new(src, options.progress.as_ref());
+    let (typ, mut input) = try!(peek_input(src, archive_type));
+    let mut buffer: Vec<u8> = vec!(0u8; options.block_size);
+    let verbose = options.verbose();
+    let mut dst = try!(open_output(dst, options));
+
+    // Decoded from the outermost layer in, which is what picks the branch
+    let outer = typ.primary();
+
+    if outer != INVALID && outer != TAR && !is_compressed(outer) {
+        try!(check_no_head(typ, options));
+    }
+
+    let result = if is_compressed(outer) ||
+                    is_parsing_tar(options) && (outer == TAR || outer == CPIO) {
+        let (inner, payload) = try!(unwrap_payload(input, typ, options));
+
+        stream_payload(inner, payload, &mut dst, &mut buffer, options)
+    } else if outer == CPIO {
+        if verbose {
+            eprintln!("Cpio file");
+        }
+
+        cpio_into_tar(input, &mut dst, options)
+    } else if outer == AR {
+        if verbose {
+            eprintln!("ar file");
+        }
+
+        ar_into_tar(input, &mut dst, options)
+    } else if outer == ZIP {
+        if verbose {
+            eprintln!("Zip file");
+        }
+
+        // Zip keeps its central directory at the end of the archive so
+        // a non-seekable source has to be spooled into memory first, the
+        // entries get the current time and root ownership
+        let mut data = Vec::new();
+        try!(input.read_to_end(&mut data));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        convert_zip(|| Ok(Cursor::new(&data[..])),
+                    &mut dst,
+                    now.as_secs(),
+                    0,
+                    0,
+                    options)
+    } else if outer == SEVENZIP {
+        if verbose {
+            eprintln!("7-Zip file");
+        }
+
+        // The 7-Zip header sits at the end of the archive too, the entries
+        // get the same treatment as those of a zip from stdin
+        let mut data = Vec::new();
+        try!(input.read_to_end(&mut data));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        sevenz_into_tar(Cursor::new(&data[..]),
+                        data.len() as u64,
+                        &mut dst,
+                        now.as_secs(),
+                        0,
+                        0,
+                        options)
+    } else if outer == TAR {
+        if verbose {
+            eprintln!("Tar file");
+        }
+
+        // Tar archives are made of whole 512 byte blocks, unless cut short
+        match try!(decode_file_into(&mut buffer, &mut dst, input, options)) {
+            copied if copied % 512 != 0 && options.head.is_none() => Err(StreamError::Truncated),
+            _ => Ok(()),
+        }
+    } else if typ == INVALID {
+        Err(StreamError::UnknownType(String::from("input stream")))
+    } else {
+        Err(StreamError::UnsupportedType(typ))
+    };
+
+    try!(result);
+
+    close_output(dst)
+}
+
+// Archive types which are converted member by member with the blocking tar
+// and zip crates, the async API only passes Tar archives through
+#[cfg(feature = "async")]
+fn requires_sync(typ: ArchiveType) -> bool {
+    typ.intersects(ZIP | SEVENZIP | CPIO | AR | RAR)
+}
+
+// Input of the async decoders, the sniffed head put back in front
+#[cfg(feature = "async")]
+type AsyncInput<R> = tokio::io::BufReader<tokio::io::Chain<Cursor<Vec<u8>>, R>>;
+
+// Async decoder for the compression layer of the input, if any
+#[cfg(feature = "async")]
+enum AsyncDecoder<R> {
+    Plain(R),
+    Gzip(AsyncGzipDecoder<R>),
+    Bzip2(AsyncBzDecoder<R>),
+    Xz(AsyncXzDecoder<R>),
+    Zstd(AsyncZstdDecoder<R>),
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncBufRead> AsyncDecoder<R> {
+    // Same as make_decoder() for the codecs async-compression has, concatenated
+    // streams decompress into one as they do there
+    fn new(input: R, typ: ArchiveType) -> Result<AsyncDecoder<R>, StreamError> {
+        if typ.contains(GZIP) {
+            let mut decoder = AsyncGzipDecoder::new(input);
+            decoder.multiple_members(true);
+            Ok(AsyncDecoder::Gzip(decoder))
+        } else if typ.contains(BZIP2) {
+            let mut decoder = AsyncBzDecoder::new(input);
+            decoder.multiple_members(true);
+            Ok(AsyncDecoder::Bzip2(decoder))
+        } else if typ.contains(XZ) {
+            let mut decoder = AsyncXzDecoder::new(input);
+            decoder.multiple_members(true);
+            Ok(AsyncDecoder::Xz(decoder))
+        } else if typ.contains(ZSTD) {
+            let mut decoder = AsyncZstdDecoder::new(input);
+            decoder.multiple_members(true);
+            Ok(AsyncDecoder::Zstd(decoder))
+        } else {
+            Err(StreamError::UnsupportedType(typ))
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncBufRead + Unpin> AsyncRead for AsyncDecoder<R> {
+    fn poll_read(self: Pin<&mut Self>,
+                 cx: &mut Context,
+                 buf: &mut ReadBuf)
+                 -> Poll<Result<(), Error>> {
+        match *self.get_mut() {
+            AsyncDecoder::Plain(ref mut inner) => Pin::new(inner).poll_read(cx, buf),
+            AsyncDecoder::Gzip(ref mut inner) => Pin::new(inner).poll_read(cx, buf),
+            AsyncDecoder::Bzip2(ref mut inner) => Pin::new(inner).poll_read(cx, buf),
+            AsyncDecoder::Xz(ref mut inner) => Pin::new(inner).poll_read(cx, buf),
+            AsyncDecoder::Zstd(ref mut inner) => Pin::new(inner).poll_read(cx, buf),
+        }
+    }
+}
+
+// Where a StreamAsync future is at
+#[cfg(feature = "async")]
+enum AsyncStage<R> {
+    // Reading the head of the input to sniff the archive type from
+    Sniffing(R),
+    // Copying the decoded input into the destination
+    Copying(AsyncDecoder<AsyncInput<R>>),
+    // Flushing the destination
+    Flushing,
+    Done,
+}
+
+// Future returned by stream_async(). The crate predates async fn, so the
+// copy loop is spelled out as a plain Future, awaited like any other
+#[cfg(feature = "async")]
+pub struct StreamAsync<R, W> {
+    stage: AsyncStage<R>,
+    dst: W,
+    archive_type: ArchiveType,
+    head: Vec<u8>,
+    head_len: usize,
+    // Decoded data between start and end is yet to be written
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize,
+    eof: bool,
+    // Decompressed payloads are sniffed before anything is written
+    checked: bool,
+    // Plain Tar input has to come in whole 512 byte blocks
+    tar: bool,
+    copied: u64,
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> StreamAsync<R, W> {
+    // Read up to SNIFF_SIZE bytes and pick the decoder by their magic the way
+    // stream_with_options() does, refusing the types it converts with the
+    // blocking tar, zip and 7-Zip crates
+    fn poll_sniff(&mut self, cx: &mut Context) -> Poll<Result<(), StreamError>> {
+        if let AsyncStage::Sniffing(ref mut src) = self.stage {
+            while self.head_len < SNIFF_SIZE {
+                let mut buf = ReadBuf::new(&mut self.head[self.head_len..]);
+
+                match Pin::new(&mut *src).poll_read(cx, &mut buf) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(StreamError::from(e))),
+                    Poll::Ready(Ok(())) if buf.filled().is_empty() => break,
+                    Poll::Ready(Ok(())) => self.head_len += buf.filled().len(),
+                }
+            }
+        }
+
+        if self.head_len == 0 {
+            return Poll::Ready(Err(StreamError::Empty));
+        }
+
+        let typ = resolve_type(self.archive_type, magic_type(&self.head[..self.head_len]));
+        let src = match std::mem::replace(&mut self.stage, AsyncStage::Done) {
+            AsyncStage::Sniffing(src) => src,
+            _ => unreachable!(),
+        };
+        let head = Cursor::new(self.head[..self.head_len].to_vec());
+        let input = tokio::io::BufReader::new(tokio::io::AsyncReadExt::chain(head, src));
+
+        let decoder = if is_compressed(typ) {
+            AsyncDecoder::new(input, typ)
+        } else if requires_sync(typ) {
+            Err(StreamError::RequiresSync(typ))
+        } else if typ.contains(TAR) {
+            self.checked = true;
+            self.tar = true;
+            Ok(AsyncDecoder::Plain(input))
+        } else if typ == INVALID {
+            Err(StreamError::UnknownType(String::from("input stream")))
+        } else {
+            Err(StreamError::UnsupportedType(typ))
+        };
+
+        match decoder {
+            Ok(decoder) => {
+                self.archive_type = typ;
+                self.stage = AsyncStage::Copying(decoder);
+                Poll::Ready(Ok(()))
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    // Move the decoded input into dst one buffer at a time until the input
+    // runs out
+    fn poll_copy(&mut self, cx: &mut Context) -> Poll<Result<(), StreamError>> {
+        let decoder = match self.stage {
+            AsyncStage::Copying(ref mut decoder) => decoder,
+            _ => unreachable!(),
+        };
+
+        loop {
+            if self.start < self.end && self.checked {
+                match Pin::new(&mut self.dst).poll_write(cx, &self.buffer[self.start..self.end]) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(StreamError::Write(e))),
+                    Poll::Ready(Ok(0)) => {
+                        let e = Error::new(ErrorKind::WriteZero, "failed to write whole buffer");
+                        return Poll::Ready(Err(StreamError::Write(e)));
+                    }
+                    Poll::Ready(Ok(written)) => {
+                        self.start += written;
+                        self.copied += written as u64;
+                    }
+                }
+                continue;
+            }
+
+            if self.eof {
+                break;
+            }
+
+            if self.start == self.end {
+                self.start = 0;
+                self.end = 0;
+            }
+
+            let mut buf = ReadBuf::new(&mut self.buffer[self.end..]);
+
+            match Pin::new(&mut *decoder).poll_read(cx, &mut buf) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(StreamError::from(e))),
+                Poll::Ready(Ok(())) if buf.filled().is_empty() => self.eof = true,
+                Poll::Ready(Ok(())) => self.end += buf.filled().len(),
+            }
+
+            // Compressed cpio archives get converted by the blocking API
+            if !self.checked && (self.eof || self.end >= SNIFF_SIZE) {
+                let inner = magic_type(&self.buffer[..self.end]);
+
+                if inner.contains(CPIO) {
+                    return Poll::Ready(Err(StreamError::RequiresSync(self.archive_type | inner)));
+                }
+
+                self.checked = true;
+            }
+        }
+
+        if self.tar && self.copied % 512 != 0 {
+            return Poll::Ready(Err(StreamError::Truncated));
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Future for StreamAsync<R, W> {
+    type Output = Result<(), StreamError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), StreamError>> {
+        let this = self.get_mut();
+
+        loop {
+            let result = match this.stage {
+                AsyncStage::Sniffing(_) => this.poll_sniff(cx),
+                AsyncStage::Copying(_) => this.poll_copy(cx),
+                AsyncStage::Flushing => {
+                    match Pin::new(&mut this.dst).poll_flush(cx) {
+                        Poll::Ready(Ok(())) => {
+                            this.stage = AsyncStage::Done;
+                            return Poll::Ready(Ok(()));
+                        }
+                        Poll::Ready(Err(e)) => Poll::Ready(Err(StreamError::Write(e))),
+                        Poll::Pending => Poll::Pending,
+                    }
+                }
+                AsyncStage::Done => return Poll::Ready(Ok(())),
+            };
+
+            match result {
+                Poll::Ready(Ok(())) => {
+                    if let AsyncStage::Copying(_) = this.stage {
+                        if this.eof {
+                            this.stage = AsyncStage::Flushing;
+                        }
+                    }
+                }
+                Poll::Ready(Err(e)) => {
+                    this.stage = AsyncStage::Done;
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+// Async counterpart of stream() for tokio readers and writers: compressed
+// Tar archives are decompressed and plain ones passed through. An archive
+// type of INVALID sniffs the type from the magic bytes. Zip, 7-Zip, cpio and
+// ar archives fail with StreamError::RequiresSync, converting them takes the
+// blocking API
+#[cfg(feature = "async")]
+pub fn stream_async<R, W>(src: R, dst: W, archive_type: ArchiveType) -> StreamAsync<R, W>
+    where R: AsyncRead + Unpin,
+          W: AsyncWrite + Unpin
+{
+    StreamAsync {
+        stage: AsyncStage::Sniffing(src),
+        dst: dst,
+        archive_type: archive_type,
+        head: vec!(0u8; SNIFF_SIZE),
+        head_len: 0,
+        buffer: vec!(0u8; MIN_BLOCK_SIZE),
+        start: 0,
+        end: 0,
+        eof: false,
+        checked: false,
+        tar: false,
+        copied: 0,
+    }
+}
+
+// Fail if a previously extracted symlink sits anywhere between root and
+// the member at path, writing through it could escape root
+fn check_no_symlink_parents(root: &Path, path: &Path) -> Result<(), StreamError> {
+    let mut current = root.to_path_buf();
+
+    if let Some(parent) = path.parent() {
+        for component in parent.components() {
+            current.push(component);
+
+            if let Ok(meta) = fs::symlink_metadata(&current) {
+                if meta.file_type().is_symlink() {
+                    return Err(StreamError::UnsafePath(path.to_string_lossy().into_owned()));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Write every member of a zip archive into the dst directory, keeping their
+// modes. Unsafe member paths are always refused as they'd land outside of dst
+fn unpack_zip<R: Read + Seek>(reader: R,
+                              dst: &Path,
+                              options: &StreamOptions)
+                              -> Result<(), StreamError> {
+    let mut decoder = try!(ZipArchive::new(reader));
+    let mut directories = Vec::new();
+
+    try!(fs::create_dir_all(dst));
+
+    for i in 0..decoder.len() {
+        let mut zf = try!(zip_member(&mut decoder, i, options));
+        let name = match strip_components(&zip_name(&zf, options), options.strip_components) {
+            Some(ref name) if is_selected(name, options) => Path::new(name).to_path_buf(),
+            _ => continue,
+        };
+
+        if !is_safe_path(&name) {
+            return Err(StreamError::UnsafePath(name.to_string_lossy().into_owned()));
+        }
+
+        try!(check_no_symlink_parents(dst, &name));
+
+        let target = dst.join(&name);
+        let mode = zip_mode(&zf);
+
+        // Replace a symlink of the same name rather than writing through it
+        if let Ok(meta) = fs::symlink_metadata(&target) {
+            if meta.file_type().is_symlink() {
+                try!(fs::remove_file(&target));
+            }
+        }
+
+        if let Some(parent) = target.parent() {
+            try!(fs::create_dir_all(parent));
+        }
+
+        if zf.is_dir() {
+            try!(fs::create_dir_all(&target));
+
+            // Applied at the end so a read-only directory still gets filled
+            directories.push((target, mode));
+        } else if mode & S_IFMT == S_IFLNK {
+            let mut link = String::new();
+            try!(zf.read_to_string(&mut link));
+            try!(make_symlink(&link, &target));
+        } else {
+            let mut file = try!(File::create(&target));
+            let mut data = try!(zip_contents(&mut zf, options));
+            try!(std::io::copy(&mut data, &mut file));
+            try!(set_mode(&target, mode));
+        }
+    }
+
+    for (directory, mode) in directories {
+        try!(set_mode(&directory, mode));
+    }
+
+    Ok(())
+}
+
+// Member path of a Tar archive relative to the extraction directory, the
+// way the tar crate unpacks: the root and '.' components are dropped. None
+// for paths with '..' components or nothing left
+fn relative_member_path(name: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::ParentDir => return None,
+            _ => {}
+        }
+    }
+
+    match path.as_os_str().is_empty() {
+        true => None,
+        false => Some(path),
+    }
+}
+
+// Write every member of a Tar archive into the dst directory, stripping
+// components and filtering them. Members with '..' components are skipped,
+// ones that would be written through a symlink an earlier member left are
+// refused. Hard links have to point at a member inside dst, with as many
+// components stripped
+fn unpack_tar<R: Read>(reader: R, dst: &Path, options: &StreamOptions) -> Result<(), StreamError> {
+    let mut archive = tar::Archive::new(reader);
+
+    for entry in try!(archive.entries()) {
+        let mut entry = try!(entry);
+        let name = try!(entry.path()).to_string_lossy().into_owned();
+
+        let name = match strip_components(&name, options.strip_components) {
+            Some(ref name) if is_selected(name, options) => relative_member_path(name),
+            _ => continue,
+        };
+        let name = match name {
+            Some(name) => name,
+            None => continue,
+        };
+
+        try!(check_no_symlink_parents(dst, &name));
+
+        let target = dst.join(&name);
+
+        // Replace a symlink of the same name rather than writing through it
+        if let Ok(meta) = fs::symlink_metadata(&target) {
+            if meta.file_type().is_symlink() {
+                try!(fs::remove_file(&target));
+            }
+        }
+
+        if let Some(parent) = target.parent() {
+            try!(fs::create_dir_all(parent));
+        }
+
+        if entry.header().entry_type().is_hard_link() {
+            let strip = options.strip_components;
+            let link = try!(entry.link_name())
+                .and_then(|link| strip_components(&link.to_string_lossy(), strip))
+                .and_then(|link| relative_member_path(&link));
+            let link = match link {
+                Some(link) => link,
+                None => return Err(StreamError::UnsafePath(name.to_string_lossy().into_owned())),
+            };
+
+            try!(check_no_symlink_parents(dst, &link));
+            try!(fs::hard_link(dst.join(&link), &target));
+            continue;
+        }
+
+        try!(entry.unpack(&target));
+    }
+
+    Ok(())
+}
+
+// Unpack the archive in src into the dst directory, peeling off a
+// compression layer first. The decompressed payload is sniffed again, so
+// a compressed tar extracts even if only the compression was detected.
+// Zip archives are spooled into memory
+pub fn extract<R: Read, P: AsRef<Path>>(src: R,
+                                        dst: P,
+                                        archive_type: ArchiveType,
+                                        options: &StreamOptions)
+                                        -> Result<(), StreamError> {
+    let src = ProgressReader::new(src, options.progress.as_ref());
+    let (typ, input) = try!(peek_input(src, archive_type));
+    let (inner, mut payload) = try!(unwrap_payload(input, typ, options));
+
+    if inner.contains(ZIP) {
+        if options.verbose() {
+            eprintln!("Extracting Zip into {}", dst.as_ref().display());
+        }
+
+        let mut data = Vec::new();
+        try!(payload.read_to_end(&mut data));
+
+        return unpack_zip(Cursor::new(data), dst.as_ref(), options);
+    }
+
+    if !inner.contains(TAR) {
+        return Err(StreamError::UnsupportedType(typ | inner));
+    }
+
+    if options.verbose() {
+        eprintln!("Extracting Tar into {}", dst.as_ref().display());
+    }
+
+    try!(fs::create_dir_all(dst.as_ref()));
+
+    unpack_tar(payload, dst.as_ref(), options)
+}
+
+// Same as extract() for a regular file, which lets zip archives be read in place
+pub fn extract_file<P: AsRef<Path>>(src: &File,
+                                    dst: P,
+                                    archive_type: ArchiveType,
+                                    options: &StreamOptions)
+                                    -> Result<(), StreamError> {
+    if !is_zip(archive_type) {
+        return extract(src, dst, archive_type, options);
+    }
+
+    try!(check_not_empty(src));
+
+    if options.verbose() {
+        eprintln!("Extracting Zip into {}", dst.as_ref().display());
+    }
+
+    let reader = try!(SharedFileReader::new(src));
+    unpack_zip(ProgressReader::new(reader, options.progress.as_ref()),
+               dst.as_ref(),
+               options)
+}
+
+// Same as stream_with_options() for a regular file, which lets zip archives
+// be read in place and their entries carry the file's mtime and ownership
+pub fn stream_file<W: Write>(src: &File,
+                             dst: W,
+                             archive_type: ArchiveType,
+                             options: &StreamOptions)
+                             -> Result<(), StreamError> {
+    if is_sevenzip(archive_type) || is_zip(archive_type) {
+        try!(check_no_head(archive_type, options));
+    }
+
+    if is_sevenzip(archive_type) {
+        return stream_sevenzip(src, dst, options);
+    }
+
+    let map = map_file(src, options);
+
+    if options.threads > 1 && cfg!(feature = "parallel-xz") && archive_type.primary() == XZ {
+        let progress = options.progress.as_ref();
+        let unwrapped = try!(match map {
+            Some(ref map) => unwrap_xz_blocks(ProgressReader::new(Cursor::new(&map[..]), progress),
+                                              archive_type,
+                                              options),
+            None => {
+                unwrap_xz_blocks(ProgressReader::new(try!(SharedFileReader::new(src)), progress),
+                                 archive_type,
+                                 options)
+            }
+        });
+
+        if let Some((inner, payload)) = unwrapped {
+            let mut buffer = vec!(0u8; options.block_size);
+            let mut dst = try!(open_output(dst, options));
+
+            try!(stream_payload(inner, payload, &mut dst, &mut buffer, options));
+            return close_output(dst);
+        }
+    }
+
+    if !is_zip(archive_type) {
+        return match map {
+            Some(ref map) => stream_with_options(&map[..], dst, archive_type, options),
+            None => stream_with_options(src, dst, archive_type, options),
+        };
+    }
+
+    try!(check_not_empty(src));
+
+    if options.verbose() {
+        eprintln!("Zip file");
+    }
+
+    let (mtime, uid, gid) = file_owner(&try!(src.metadata()));
+    let progress = options.progress.as_ref();
+    let mut dst = try!(open_output(dst, options));
+
+    try!(match map {
+        Some(ref map) => {
+            convert_zip(|| Ok(ProgressReader::new(Cursor::new(&map[..]), progress)),
+                        &mut dst,
+                        mtime,
+                        uid,
+                        gid,
+                        options)
+        }
+        None => {
+            convert_zip(|| SharedFileReader::new(src).map(|r| ProgressReader::new(r, progress)),
+                        &mut dst,
+                        mtime,
+                        uid,
+                        gid,
+                        options)
+        }
+    });
+
+    close_output(dst)
+}
+
+// Same as stream_file() into a regular file. Plain Tar archives, when there
+// are no progress reports, checksum, recompression, filters, manifest, size
+// limit or head to get in the way, are copied by std::io::copy(), which has
+// Linux move the data inside the kernel with copy_file_range(2) or sendfile(2)
+pub fn stream_file_to_file(src: &File,
+                           dst: &mut File,
+                           archive_type: ArchiveType,
+                           options: &StreamOptions)
+                           -> Result<(), StreamError> {
+    let raw = archive_type == TAR && options.progress.is_none() && options.checksum.is_none() &&
+              options.recompress == INVALID && !is_parsing_tar(options) &&
+              options.max_size.is_none() && options.head.is_none() && options.verbosity < 2;
+
+    if !raw {
+        return stream_file(src, dst, archive_type, options);
+    }
+
+    try!(check_not_empty(src));
+
+    if options.verbose() {
+        eprintln!("Tar file");
+    }
+
+    // Tar archives are made of whole 512 byte blocks
+    match try!(std::io::copy(&mut &*src, dst)) {
+        copied if copied % 512 != 0 => Err(StreamError::Truncated),
+        _ => Ok(()),
+    }
+}
+
+// Continue a conversion into dst that was cut short, keeping what dst holds
+// already. Plain Tar archives are copied on from the same offset of src,
+// compressed ones are decompressed from the start and their output thrown
+// away up to that offset. Other archives are converted member by member, so
+// the offset doesn't tell where to pick up, and neither does compressed or
+// checksummed output: dst is started over then, with a warning
+pub fn resume_file_to_file(src: &File,
+                           dst: &mut File,
+                           archive_type: ArchiveType,
+                           options: &StreamOptions)
+                           -> Result<(), StreamError> {
+    let done = try!(dst.seek(SeekFrom::End(0)));
+    let plain = options.checksum.is_none() && options.recompress == INVALID &&
+                !is_parsing_tar(options);
+    let resumable = plain && (archive_type == TAR ||
+                              is_compressed(archive_type) &&
+                              !archive_type.intersects(ZIP | SEVENZIP | CPIO | AR | RAR));
+
+    if done == 0 {
+        return stream_file_to_file(src, dst, archive_type, options);
+    }
+
+    if !resumable {
+        return start_over(src, dst, archive_type, options);
+    }
+
+    if options.verbose() {
+        eprintln!("Resuming after {} bytes", done);
+    }
+
+    let copied = if archive_type == TAR {
+        if done > try!(src.metadata()).len() {
+            return Err(StreamError::Io(Error::new(ErrorKind::InvalidData,
+                                                  "the partial output is larger than SRC")));
+        }
+
+        if let Some(ref progress) = options.progress {
+            progress.add(done);
+        }
+
+        try!((&*src).seek(SeekFrom::Start(done)));
+        try!(std::io::copy(&mut ProgressReader::new(src, options.progress.as_ref()), dst))
+    } else {
+        let input = ProgressReader::new(src, options.progress.as_ref());
+        let (inner, mut payload) = try!(unwrap_payload(input, archive_type, options));
+
+        // Compressed cpio archives get converted as well
+        if inner.contains(CPIO) {
+            drop(payload);
+            try!((&*src).seek(SeekFrom::Start(0)));
+            return start_over(src, dst, archive_type | inner, options);
+        }
+
+        let skipped = try!(std::io::copy(&mut payload.by_ref().take(done), &mut std::io::sink()));
+
+        if skipped < done {
+            return Err(StreamError::Io(Error::new(ErrorKind::InvalidData,
+                                                  "the partial output is larger than the \
+                                                   decompressed SRC")));
+        }
+
+        try!(std::io::copy(&mut payload, dst))
+    };
+
+    // Tar archives are made of whole 512 byte blocks
+    if archive_type == TAR && (done + copied) % 512 != 0 {
+        return Err(StreamError::Truncated);
+    }
+
+    Ok(())
+}
+
+// Throw away what resume_file_to_file() can't continue and convert src anew
+fn start_over(src: &File,
+              dst: &mut File,
+              archive_type: ArchiveType,
+              options: &StreamOptions)
+              -> Result<(), StreamError> {
+    eprintln!("Warning: can't resume converting {} archives, starting over",
+              archive_type);
+
+    try!(dst.set_len(0));
+    try!(dst.seek(SeekFrom::Start(0)));
+
+    stream_file_to_file(src, dst, archive_type, options)
+}
+
+// What append_files() does with a member named like one it appended already
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Collision {
+    // Leave the later member out
+    Skip,
+    // Append the later member as NAME.1, or NAME.2 if that's taken as well
+    Rename,
+    // Fail with StreamError::Collision
+    Error,
+}
+
+impl Collision {
+    // Name is one of skip, rename or error, None for anything else
+    pub fn from_name(name: &str) -> Option<Collision> {
+        match name.to_lowercase().as_str() {
+            "skip" => Some(Collision::Skip),
+            "rename" => Some(Collision::Rename),
+            "error" => Some(Collision::Error),
+            _ => None,
+        }
+    }
+}
+
+// Chunks of converted data a source gets ahead of the appending
+const PIPE_DEPTH: usize = 4;
+
+// Writer handing the conversion of an appended source over to the thread
+// appending its members
+struct PipeWriter(mpsc::SyncSender<Vec<u8>>);
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self.0.send(buf.to_vec()) {
+            Ok(()) => Ok(buf.len()),
+            Err(_) => Err(Error::new(ErrorKind::BrokenPipe, "appending stopped")),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+// Reader of what a PipeWriter was handed, at its end once the writer is gone
+struct PipeReader {
+    receiver: mpsc::Receiver<Vec<u8>>,
+    chunk: Cursor<Vec<u8>>,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.chunk.position() as usize == self.chunk.get_ref().len() {
+            match self.receiver.recv() {
+                Ok(chunk) => self.chunk = Cursor::new(chunk),
+                Err(_) => return Ok(0),
+            }
+        }
+
+        self.chunk.read(buf)
+    }
+}
+
+// Give a member the new name, header being its own and pending the extension
+// entries before it. GNU long names of the old name are dropped and PAX path
+// records changed. Returns the name for a GNU long name entry when neither
+// the header nor a PAX record holds it
+fn rename_member(header: &mut Header,
+                 pending: &mut Vec<(Header, Vec<u8>)>,
+                 name: &str)
+                 -> Option<String> {
+    let mut in_pax = false;
+    pending.retain(|&(ref header, _)| !header.entry_type().is_gnu_longname());
+
+    for &mut (ref mut pax_header, ref mut data) in pending.iter_mut() {
+        if pax_header.entry_type().is_pax_local_extensions() && pax_path(data).is_some() {
+            *data = pax_renamed(data, name);
+            pax_header.set_size(data.len() as u64);
+            pax_header.set_cksum();
+            in_pax = true;
+        }
+    }
+
+    if let Some(ustar) = header.as_ustar_mut() {
+        ustar.prefix = [0; 155];
+    }
+
+    let long_name = if header.set_path(name).is_err() {
+        header.as_old_mut().name = [0; 100];
+        copy_truncated(&mut header.as_old_mut().name, name);
+
+        if in_pax { None } else { Some(name.to_string()) }
+    } else {
+        None
+    };
+
+    header.set_cksum();
+    long_name
+}
+
+// Append the members of a Tar archive to builder, names holding the ones
+// appended before. Members named like one of them are dealt with by
+// collision, directories are appended only once
+fn append_tar_members<R: Read, W: Write>(reader: R,
+                                         builder: &mut Builder<W>,
+                                         names: &mut HashSet<String>,
+                                         collision: Collision,
+                                         options: &StreamOptions)
+                                         -> Result<(), StreamError> {
+    let mut archive = tar::Archive::new(reader);
+    let mut pending: Vec<(Header, Vec<u8>)> = Vec::new();
+    let mut long_name = None;
+    let mut long_path = None;
+
+    for entry in try!(archive.entries()).raw(true) {
+        let mut entry = try!(entry);
+        let mut header = entry.header().clone();
+        let kind = header.entry_type();
+
+        if kind.is_gnu_longname() || kind.is_gnu_longlink() || kind.is_pax_local_extensions() {
+            let mut data = Vec::new();
+            try!(entry.read_to_end(&mut data));
+
+            if kind.is_gnu_longname() {
+                long_name = Some(String::from_utf8_lossy(&data)
+                    .trim_right_matches('\0')
+                    .to_string());
+            } else if kind.is_pax_local_extensions() {
+                long_path = pax_path(&data);
+            }
+
+            pending.push((header, data));
+            continue;
+        }
+
+        // PAX records override GNU long names, which override the header
+        let mut name = match long_path.take().or(long_name.take()) {
+            Some(name) => name,
+            None => try!(header.path()).to_string_lossy().into_owned(),
+        };
+        let mut renamed = None;
+
+        // Directories come with or without a trailing slash
+        if !kind.is_pax_global_extensions() &&
+           !names.insert(name.trim_right_matches('/').to_string()) {
+            match collision {
+                _ if kind.is_dir() => {
+                    pending.clear();
+                    continue;
+                }
+                Collision::Skip => {
+                    if options.verbose() {
+                        eprintln!("Skipping '{}', it was appended already", name);
+                    }
+
+                    pending.clear();
+                    continue;
+                }
+                Collision::Rename => {
+                    name = (1..)
+                        .map(|n| format!("{}.{}", name, n))
+                        .find(|renamed| !names.contains(renamed))
+                        .unwrap();
+                    names.insert(name.clone());
+                    renamed = rename_member(&mut header, &mut pending, &name);
+                }
+                Collision::Error => return Err(StreamError::Collision(name)),
+            }
+        }
+
+        for (pending_header, data) in pending.drain(..) {
+            try!(builder.append(&pending_header, &data[..]));
+        }
+
+        if let Some(ref renamed) = renamed {
+            try!(append_long_name(builder, b'L', renamed));
+        }
+
+        match options.manifest {
+            Some(ref manifest) if !kind.is_pax_global_extensions() => {
+                let checksum = manifest.checksum();
+
+                try!(builder.append(&header,
+                                    ChecksumReader {
+                                        inner: &mut entry,
+                                        checksum: checksum.as_ref(),
+                                    }));
+                manifest.record(&name,
+                                try!(header.size()),
+                                header.mode().unwrap_or(0),
+                                checksum);
+            }
+            _ => try!(builder.append(&header, &mut entry)),
+        }
+    }
+
+    Ok(())
+}
+
+// Convert every source into one Tar archive written to dst, its members
+// following each other the way sources do, like tar --concatenate of their
+// conversions would have them. A source is converted by stream_file() with
+// its own options on a thread of its own, while its members are appended.
+// options are those of the output, its recompression, checksum and manifest
+pub fn append_files<W: Write>(sources: &[(&File, ArchiveType, &StreamOptions)],
+                              dst: W,
+                              collision: Collision,
+                              options: &StreamOptions)
+                              -> Result<(), StreamError> {
+    let mut builder = Builder::new(try!(open_output(dst, options)));
+    let mut names = HashSet::new();
+
+    for &(src, archive_type, source_options) in sources {
+        let (sender, receiver) = mpsc::sync_channel(PIPE_DEPTH);
+        let mut reader = PipeReader {
+            receiver: receiver,
+            chunk: Cursor::new(Vec::new()),
+        };
+
+        try!(thread::scope(|scope| -> Result<(), StreamError> {
+            let converter = scope.spawn(move || {
+                stream_file(src, PipeWriter(sender), archive_type, source_options)
+            });
+            let appended = append_tar_members(&mut reader,
+                                              &mut builder,
+                                              &mut names,
+                                              collision,
+                                              options)
+                .and_then(|_| Ok(try!(std::io::copy(&mut reader, &mut std::io::sink()))));
+
+            // Leaving the source behind fails its conversion, which isn't what went wrong
+            drop(reader);
+            match (appended, converter.join()) {
+                (Err(e), _) => Err(e),
+                (Ok(_), Ok(converted)) => converted,
+                (Ok(_), Err(_)) => {
+                    Err(StreamError::Io(Error::new(ErrorKind::Other,
+                                                   "append worker thread died")))
+                }
+            }
+        }));
+    }
+
+    close_output(try!(builder.into_inner()))
+}
+
+// Memory map src if options ask for it, None if they don't or the file can't
+// be mapped, which leaves it to regular reads. The file mustn't be truncated
+// while it's mapped, reading past its new end kills the process with SIGBUS
+fn map_file(src: &File, options: &StreamOptions) -> Option<Mmap> {
+    if !options.mmap {
+        return None;
+    }
+
+    match unsafe { Mmap::map(src) } {
+        Ok(map) => Some(map),
+        Err(e) => {
+            if options.verbose() {
+                eprintln!("Unable to memory map the source, reading it instead: {}", e);
+            }
+
+            None
+        }
+    }
+}
+
+// Same as stream_file() for a 7-Zip archive, read in place
+fn stream_sevenzip<W: Write>(src: &File,
+                             dst: W,
+                             options: &StreamOptions)
+                             -> Result<(), StreamError> {
+    try!(check_not_empty(src));
+
+    if options.verbose() {
+        eprintln!("7-Zip file");
+    }
+
+    let meta = try!(src.metadata());
+    let (mtime, uid, gid) = file_owner(&meta);
+    let reader = try!(SharedFileReader::new(src));
+    let mut dst = try!(open_output(dst, options));
+    try!(sevenz_into_tar(ProgressReader::new(reader, options.progress.as_ref()),
+                         meta.len(),
+                         &mut dst,
+                         mtime,
+                         uid,
+                         gid,
+                         options));
+
+    close_output(dst)
+}
+
+// Report every member of a zip archive to the callback
+fn list_zip<R: Read + Seek, F: FnMut(&ListEntry)>(reader: R,
+                                                  options: &StreamOptions,
+                                                  callback: &mut F)
+                                                  -> Result<(), StreamError> {
+    let mut decoder = try!(ZipArchive::new(reader));
+
+    for i in 0..decoder.len() {
+        let zf = try!(decoder.by_index_raw(i));
+        let extra = zip_unix_extra(zf.extra_data());
+
+        callback(&ListEntry {
+            name: zip_name(&zf, options),
+            size: zf.size(),
+            mode: zip_mode(&zf),
+            mtime: extra.mtime.or_else(|| zip_mtime(&zf)).unwrap_or(0),
+            is_dir: zf.is_dir(),
+            compressed_size: Some(zf.compressed_size()),
+        });
+    }
+
+    Ok(())
+}
+
+// Report every member of a Tar archive to the callback
+fn list_tar<R: Read, F: FnMut(&ListEntry)>(reader: R,
+                                           callback: &mut F)
+                                           -> Result<(), StreamError> {
+    let mut archive = tar::Archive::new(reader);
+
+    for entry in try!(archive.entries()) {
+        let entry = try!(entry);
+        let header = entry.header();
+
+        callback(&ListEntry {
+            name: try!(entry.path()).to_string_lossy().into_owned(),
+            size: try!(header.size()),
+            mode: try!(header.mode()),
+            mtime: try!(header.mtime()),
+            is_dir: header.entry_type().is_dir(),
+            compressed_size: None,
+        });
+    }
+
+    Ok(())
+}
+
+// Report the members of the src archive to the callback without writing
+// anything. Compressed tars are decompressed on the fly, zip archives are
+// spooled into memory
+pub fn list<R: Read, F: FnMut(&ListEntry)>(src: R,
+                                           archive_type: ArchiveType,
+                                           options: &StreamOptions,
+                                           mut callback: F)
+                                           -> Result<(), StreamError> {
+    let (typ, input) = try!(peek_input(src, archive_type));
+    let (inner, mut payload) = try!(unwrap_payload(input, typ, options));
+
+    if inner.contains(ZIP) {
+        let mut data = Vec::new();
+        try!(payload.read_to_end(&mut data));
+        list_zip(Cursor::new(data), options, &mut callback)
+    } else if inner.contains(TAR) {
+        list_tar(payload, &mut callback)
+    } else {
+        Err(StreamError::UnsupportedType(typ | inner))
+    }
+}
+
+// Same as list() for a regular file, which lets zip archives be read in place
+pub fn list_file<F: FnMut(&ListEntry)>(src: &File,
+                                       archive_type: ArchiveType,
+                                       options: &StreamOptions,
+                                       mut callback: F)
+                                       -> Result<(), StreamError> {
+    if is_zip(archive_type) {
+        try!(check_not_empty(src));
+        list_zip(src, options, &mut callback)
+    } else {
+        list(src, archive_type, options, callback)
+    }
+}
+
+// Copy the contents of the named zip member into dst
+fn member_zip<R: Read + Seek, W: Write>(reader: R,
+                                        mut dst: W,
+                                        name: &str,
+                                        options: &StreamOptions)
+                                        -> Result<(), StreamError> {
+    let mut decoder = try!(ZipArchive::new(reader));
+    let mut zf = match options.encoding {
+        // Decoded names don't match the ones the zip crate looks up
+        Some(_) => {
+            let mut found = None;
+
+            for i in 0..decoder.len() {
+                if zip_name(&try!(decoder.by_index_raw(i)), options) == name {
+                    found = Some(i);
+                    break;
+                }
+            }
+
+            match found {
+                Some(i) => try!(zip_member(&mut decoder, i, options)),
+                None => return Err(StreamError::MemberNotFound(name.to_string())),
+            }
+        }
+        None => {
+            let opened = match options.password {
+                Some(ref password) => decoder.by_name_decrypt(name, password),
+                None => decoder.by_name(name).map(Ok),
+            };
+
+            match opened {
+                Ok(Ok(zf)) => zf,
+                Ok(Err(_)) => return Err(StreamError::BadPassword(name.to_string())),
+                Err(ZipError::FileNotFound) => {
+                    return Err(StreamError::MemberNotFound(name.to_string()))
+                }
+                Err(ZipError::UnsupportedArchive(ZipError::PASSWORD_REQUIRED)) => {
+                    return Err(StreamError::Encrypted(name.to_string()))
+                }
+                Err(e) => return Err(StreamError::from(e)),
+            }
+        }
+    };
+
+    let mut data = try!(zip_contents(&mut zf, options));
+    try!(std::io::copy(&mut data, &mut dst));
+    try!(dst.flush());
+
+    Ok(())
+}
+
+// Copy the contents of the first Tar member named name into dst
+fn member_tar<R: Read, W: Write>(reader: R, mut dst: W, name: &str) -> Result<(), StreamError> {
+    let mut archive = tar::Archive::new(reader);
+
+    for entry in try!(archive.entries()) {
+        let mut entry = try!(entry);
+
+        if try!(entry.path()) == Path::new(name) {
+            try!(std::io::copy(&mut entry, &mut dst));
+            try!(dst.flush());
+
+            return Ok(());
+        }
+    }
+
+    Err(StreamError::MemberNotFound(name.to_string()))
+}
+
+// Write the contents of a single member of the src archive into dst as is,
+// without wrapping it into a Tar archive. Compressed tars are decompressed on
+// the fly, zip archives are spooled into memory
+pub fn extract_member<R: Read, W: Write>(src: R,
+                                         dst: W,
+                                         name: &str,
+                                         archive_type: ArchiveType,
+                                         options: &StreamOptions)
+                                         -> Result<(), StreamError> {
+    let src = ProgressReader::new(src, options.progress.as_ref());
+    let (typ, input) = try!(peek_input(src, archive_type));
+    let (inner, mut payload) = try!(unwrap_payload(input, typ, options));
+
+    if inner.contains(ZIP) {
+        let mut data = Vec::new();
+        try!(payload.read_to_end(&mut data));
+        member_zip(Cursor::new(data), dst, name, options)
+    } else if inner.contains(TAR) {
+        member_tar(payload, dst, name)
+    } else {
+        Err(StreamError::UnsupportedType(typ | inner))
+    }
+}
+
+// Same as extract_member() for a regular file, which lets zip archives be
+// read in place
+pub fn extract_member_file<W: Write>(src: &File,
+                                     dst: W,
+                                     name: &str,
+                                     archive_type: ArchiveType,
+                                     options: &StreamOptions)
+                                     -> Result<(), StreamError> {
+    if is_zip(archive_type) {
+        try!(check_not_empty(src));
+        let reader = try!(SharedFileReader::new(src));
+        member_zip(ProgressReader::new(reader, options.progress.as_ref()),
+                   dst,
+                   name,
+                   options)
+    } else {
+        extract_member(src, dst, name, archive_type, options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mime_types_map_to_flags() {
+        assert_eq!(mime_type("application/x-tar"), TAR);
+        assert_eq!(mime_type("application/gzip"), GZIP);
+        assert_eq!(mime_type("application/x-gzip"), GZIP);
+        assert_eq!(mime_type("application/zip"), ZIP);
+        assert_eq!(mime_type("application/x-xz"), XZ);
+        assert_eq!(mime_type("application/x-bzip2"), BZIP2);
+        assert_eq!(mime_type("application/zstd"), ZSTD);
+        assert_eq!(mime_type("application/x-7z-compressed"), SEVENZIP);
+        assert_eq!(mime_type("application/vnd.debian.binary-package"), AR);
+        assert_eq!(mime_type("text/plain"), INVALID);
+        assert_eq!(mime_type("cannot open `x' (No such file or directory)"), INVALID);
+    }
+
+    #[test]
+    fn magic_bytes_map_to_flags() {
+        let mut tar = vec![0u8; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+
+        assert_eq!(magic_type(&tar), TAR);
+        assert_eq!(magic_type(&[0x1f, 0x8b, 8, 0]), GZIP);
+        assert_eq!(magic_type(b"PK\x03\x04"), ZIP);
+        assert_eq!(magic_type(b"PK\x05\x06"), ZIP);
+        assert_eq!(magic_type(b"BZh9"), BZIP2);
+        assert_eq!(magic_type(b"070701"), CPIO);
+        assert_eq!(magic_type(b"!<arch>\n"), AR);
+        assert_eq!(magic_type(&[0x1f]), INVALID);
+        assert_eq!(magic_type(b""), INVALID);
+    }
+
+    #[test]
+    fn exit_codes_tell_failures_apart() {
+        let missing = Error::new(ErrorKind::NotFound, "gone");
+        let checksum = Error::new(ErrorKind::Other, TAR_CHECKSUM_MISMATCH);
+
+        assert_eq!(StreamError::Io(Error::new(ErrorKind::Other, "oops")).exit_code(), 1);
+        assert_eq!(StreamError::Io(missing).exit_code(), 17);
+        assert_eq!(StreamError::UnknownType(String::from("x")).exit_code(), 2);
+        assert_eq!(StreamError::Corrupt(String::from("bad crc")).exit_code(), 10);
+        assert_eq!(StreamError::from(ZipError::InvalidArchive("bad header")).exit_code(), 10);
+        assert_eq!(StreamError::from(checksum).exit_code(), 10);
+        assert_eq!(StreamError::Collision(String::from("x")).exit_code(), 16);
+    }
+
+    #[test]
+    fn layers_go_from_the_outside_in() {
+        assert_eq!((TAR | GZIP).layers(), vec![GZIP, TAR]);
+        assert_eq!((TAR | ZIP | XZ).layers(), vec![XZ, ZIP, TAR]);
+        assert_eq!((CPIO | ZSTD).primary(), ZSTD);
+        assert_eq!(INVALID.primary(), INVALID);
+        assert_eq!((TAR | BZIP2 | AUTO).to_string(), "bzip2, tar, auto");
+    }
+
+    #[test]
+    fn file_names_map_to_flags() {
+        let named = |path: &str| {
+            let mut typ = INVALID;
+            set_filename_flags(path, &mut typ);
+            typ
+        };
+
+        assert_eq!(named("some.tar"), TAR);
+        assert_eq!(named("some.tgz"), TAR | GZIP);
+        assert_eq!(named("some.tar.gz"), TAR | GZIP);
+        assert_eq!(named("some.tar.bz2"), TAR | BZIP2);
+        assert_eq!(named("some.txz"), TAR | XZ);
+        assert_eq!(named("some.zip"), ZIP);
+        assert_eq!(named("some.tbz2"), TAR | BZIP2);
+        assert_eq!(named("backups.zip/data.tar.gz"), TAR | GZIP);
+        assert_eq!(named("old.bz2.d/notes.txz"), TAR | XZ);
+        assert_eq!(named("backups.rar/notes.br"), BROTLI);
+        assert_eq!(named("old.7z.d/x.tar.gz"), TAR | GZIP);
+        assert_eq!(named("some.tar.d/notes.txt"), INVALID);
+        assert_eq!(named("some.tZ"), TAR | COMPRESS);
+        assert_eq!(named("some.tar.zst"), TAR | ZSTD);
+        assert_eq!(named("some.tar.br"), TAR | BROTLI);
+        assert_eq!(named("some.tar.lz"), TAR | LZIP);
+        assert_eq!(named("some.tar.lz4"), TAR | LZ4);
+        assert_eq!(named("some.tzo"), TAR | LZOP);
+        assert_eq!(named("some.deb"), AR);
+        assert_eq!(named("README"), INVALID);
+    }
+
+    #[test]
+    fn pax_records_count_their_own_length() {
+        assert_eq!(pax_record("path", "a"), "9 path=a\n");
+
+        // 99 bytes without the length, which takes three digits then
+        let record = pax_record("path", &"x".repeat(92));
+        assert_eq!(record.len(), 102);
+        assert!(record.starts_with("102 "));
+    }
+
+    #[test]
+    fn oversized_members_get_their_size_from_a_pax_record() {
+        let options = StreamOptions::default();
+        let size = USTAR_MAX_SIZE + 1;
+        let mut member = Member::new(String::from("big.bin"), &options).unwrap();
+        let mut builder = Builder::new(Vec::new());
+
+        member.header.set_size(0);
+        member.header.set_cksum();
+        // Only the headers matter, the data isn't checked against the size
+        append_member(&mut builder, &member, size, &[][..], &options).unwrap();
+        let output = builder.into_inner().unwrap();
+        let header = &output[1024..1536];
+
+        // An 'x' header and its records, then the member with a size of 0
+        assert_eq!(output[156], b'x');
+        assert!(output[512..1024].starts_with(b"19 size=8589934592\n"));
+        assert!(header.starts_with(b"big.bin\0"));
+        assert_eq!(&header[124..135], b"00000000000");
+    }
+
+    #[test]
+    fn large_zip_members_are_deferred_by_the_workers() {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let deflated = zip::write::FileOptions::default();
+
+        writer.start_file("small.bin", deflated).unwrap();
+        writer.write_all(b"small").unwrap();
+        writer.start_file("large.bin", deflated).unwrap();
+        // Zeros compress to next to nothing, the zip stays small
+        for _ in 0..PARALLEL_BUFFER_LIMIT / (1 << 20) + 1 {
+            writer.write_all(&[0u8; 1 << 20]).unwrap();
+        }
+        let data = writer.finish().unwrap();
+        let mut archive = ZipArchive::new(data).unwrap();
+        let options = StreamOptions { threads: 2, ..Default::default() };
+
+        match read_zip_entry(&mut archive, 0, 0, 0, 0, &options).unwrap() {
+            ZipMember::Buffered(_, data) => assert_eq!(data, b"small"),
+            _ => panic!("expected small.bin to be buffered"),
+        }
+        match read_zip_entry(&mut archive, 1, 0, 0, 0, &options).unwrap() {
+            ZipMember::Deferred(member) => assert_eq!(member.path, "large.bin"),
+            _ => panic!("expected large.bin to be deferred"),
+        }
+    }
+
+    #[test]
+    fn transforms_rewrite_like_sed() {
+        let prefix = Transform::new("s,^,project/,").unwrap();
+        let groups = Transform::new(r"s/(\w+)\.TXT$/\1.txt/").unwrap();
+        let global = Transform::new("s/a/b/g").unwrap();
+
+        assert_eq!(prefix.apply("a.txt"), "project/a.txt");
+        assert_eq!(groups.apply("dir/NOTES.TXT"), "dir/NOTES.txt");
+        assert_eq!(global.apply("banana"), "bbnbnb");
+        assert!(Transform::new("s/a/b").is_err());
+    }
+
+    #[test]
+    fn tar_variants_go_by_the_first_header() {
+        let variant = |mut header: Header| {
+            header.set_path("a").unwrap();
+            header.set_cksum();
+            tar_variant(header.as_bytes())
+        };
+        let mut pax = Header::new_ustar();
+        pax.set_entry_type(EntryType::XHeader);
+
+        assert_eq!(variant(Header::new_old()), Some(TarVariant::V7));
+        assert_eq!(variant(Header::new_ustar()), Some(TarVariant::Ustar));
+        assert_eq!(variant(pax), Some(TarVariant::Pax));
+        assert_eq!(variant(Header::new_gnu()), Some(TarVariant::Gnu));
+        assert_eq!(tar_variant(&[0u8; 512]), None);
+    }
+
+    #[test]
+    fn chunked_hands_out_chunks_of_the_buffer_size() {
+        let mut buffer = [0u8; 4];
+        let mut chunks = Vec::new();
+        let total = Cursor::new(b"0123456789".to_vec())
+            .chunked(&mut buffer, |chunk| -> Result<_, Error> {
+                chunks.push(chunk.to_vec());
+                Ok(ControlFlow::Continue(()))
+            })
+            .unwrap();
+
+        assert_eq!(total, 10);
+        assert_eq!(chunks, vec![b"0123".to_vec(), b"4567".to_vec(), b"89".to_vec()]);
+    }
+
+    #[test]
+    fn chunked_stops_at_a_callback_error() {
+        let mut buffer = [0u8; 4];
+        let mut calls = 0;
+        let result = Cursor::new(b"0123456789".to_vec()).chunked(&mut buffer, |_| {
+            calls += 1;
+            Err(StreamError::Write(Error::new(ErrorKind::Other, "full")))
+        });
+
+        match result {
+            Err(StreamError::Write(_)) => (),
+            other => panic!("expected Write, got {:?}", other),
+        }
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn chunked_stops_when_the_callback_breaks() {
+        let mut buffer = [0u8; 4];
+        let mut input = Cursor::new(b"0123456789".to_vec());
+        let stop_at_4 = |chunk: &[u8]| -> Result<_, Error> {
+            match chunk.starts_with(b"4") {
+                true => Ok(ControlFlow::Break(())),
+                false => Ok(ControlFlow::Continue(())),
+            }
+        };
+        let total = input.chunked(&mut buffer, stop_at_4).unwrap();
+
+        // The chunk the callback broke at counts, the rest is left unread
+        assert_eq!(total, 8);
+        assert_eq!(input.position(), 8);
+    }
+}
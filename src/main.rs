@@ -1,282 +1,1431 @@
-#[macro_use]
-extern crate bitflags;
 extern crate getopts;
-extern crate flate2;
-extern crate tar;
-extern crate xz2;
-extern crate zip;
-extern crate bzip2;
+extern crate glob;
+extern crate tar_streamer;
+extern crate encoding_rs;
+extern crate serde;
+extern crate serde_json;
+extern crate ctrlc;
+#[cfg(feature = "net")]
+extern crate reqwest;
 
 use std::env;
+use std::fs;
 use std::fs::File;
 use std::path::Path;
-use std::io::{Read, Write, Error};
+use std::io::{Read, Write, Error, ErrorKind};
+use std::sync::Mutex;
 use std::ascii::AsciiExt;
-use std::process::Command;
-use std::os::unix::fs::MetadataExt;
 use getopts::Options;
-use zip::ZipArchive;
-use bzip2::read::BzDecoder;
-use xz2::read::XzDecoder;
-use flate2::read::GzDecoder;
-use tar::{Builder, Header};
-
-bitflags! {
-    flags ArchiveType: u32 {
-        const INVALID = 0b00000000,
-        const TAR     = 0b00000001,
-        const GZIP    = 0b00000010,
-        const ZIP     = 0b00000100,
-        const XZ      = 0b00001000, 
-        const BZIP2   = 0b00010000,
-        const _ALL    = (0b00010000 << 1) - 1
-    }
-}
-
-// Chunked trait for reading in chunks of size of the buffer
-trait Chunked {
-    fn chunked<F>(&mut self, mut buffer: &mut [u8], mut callback: F) -> Result<usize, Error>
-        where F: FnMut(&[u8], usize);
-}
-
-// Implement the Chunked trait for the Read trait
-impl<R: Read> Chunked for R {
-    fn chunked<F>(&mut self, mut buffer: &mut [u8], mut callback: F) -> Result<usize, Error>
-        where F: FnMut(&[u8], usize)
-    {
-        let mut read_total = 0usize;
-
-        loop {
-            let read = try!(self.read(&mut buffer));
-            read_total += read;
-
-            if read > 0 {
-                callback(&buffer, read);
-            } else {
-                break;
-            }
+use glob::Pattern;
+use encoding_rs::Encoding;
+use serde::Serialize;
+use tar_streamer::{ArchiveType, Archives, Checksum, Collision, Manifest, Progress, SizeLimit,
+                   SkippedEntries, StreamError, StreamOptions, TarFormat, TarVariant, Transform,
+                   INVALID, DEFAULT_BLOCK_SIZE, MIN_BLOCK_SIZE};
+
+static VERSION: &'static str = "0.1.0";
+
+// Exit code of invalid options and arguments, EX_USAGE of sysexits.h
+const USAGE: i32 = 64;
+
+// Less verbose version of the panic!() macro for invalid options and arguments
+fn error(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(USAGE);
+}
+
+// Exit code of a conversion that finished without the members it skipped
+const COMPLETED_WITH_ERRORS: i32 = 15;
+
+// Exit code after Ctrl-C, 128 + SIGINT the way shells report it
+const INTERRUPTED: i32 = 130;
+
+// Partial output of the conversion going on, which the SIGINT handler
+// removes. None while there's none or it's kept for --resume
+static IN_PROGRESS: Mutex<Option<String>> = Mutex::new(None);
+
+// Have Ctrl-C remove the partial output instead of leaving it behind
+fn handle_interrupts() {
+    let result = ctrlc::set_handler(|| {
+        if let Some(partial) = IN_PROGRESS.lock().unwrap().take() {
+            let _ = fs::remove_file(partial);
         }
 
-        Ok(read_total)
-    }
-}
-
-struct ArchiveClass<'a> {
-    class: ArchiveType,
-    type_name: &'a str,
-    file_fingerprint: &'a str,
-}
-
-#[allow(non_upper_case_globals)]
-static Archives: [ArchiveClass<'static>; 5] = [ArchiveClass {
-                                                   class: TAR,
-                                                   type_name: "tar",
-                                                   file_fingerprint: "tar archive",
-                                               },
-                                               ArchiveClass {
-                                                   class: GZIP,
-                                                   type_name: "gzip",
-                                                   file_fingerprint: "gzip compressed data",
-                                               },
-                                               ArchiveClass {
-                                                   class: ZIP,
-                                                   type_name: "zip",
-                                                   file_fingerprint: "Zip archive data",
-                                               },
-                                               ArchiveClass {
-                                                   class: XZ,
-                                                   type_name: "xz",
-                                                   file_fingerprint: "XZ compressed data",
-                                               },
-                                               ArchiveClass {
-                                                   class: BZIP2,
-                                                   type_name: "bzip2",
-                                                   file_fingerprint: "bzip2 compressed data",
-                                               }];
+        eprintln!("Interrupted");
+        std::process::exit(INTERRUPTED);
+    });
 
-static VERSION: &'static str = "0.1.0";
+    if let Err(e) = result {
+        eprintln!("Warning: can't handle interrupts: {}", e);
+    }
+}
+
+// Print the error and exit with its exit code
+fn exit_with(e: StreamError) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(e.exit_code());
+}
 
-// Less verbose version of the panic!() macro
-fn error(message: &str) {
-    println!("{}", message);
-    std::process::exit(1);
+// Why a single SRC DST pair couldn't be converted
+enum Failure {
+    // Refused before streaming started, exits with USAGE
+    Usage(String),
+    Stream(StreamError),
+    // Finished with this many members left out by --skip-errors
+    Incomplete(usize),
+}
+
+impl Failure {
+    fn message(&self) -> String {
+        match *self {
+            Failure::Usage(ref message) => message.clone(),
+            Failure::Stream(ref e) => e.to_string(),
+            Failure::Incomplete(count) => format!("{} members skipped with errors", count),
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match *self {
+            Failure::Usage(_) => USAGE,
+            Failure::Stream(ref e) => e.exit_code(),
+            Failure::Incomplete(_) => COMPLETED_WITH_ERRORS,
+        }
+    }
+}
+
+// Report the failure and exit with its code
+fn exit_failure(failure: Failure) -> ! {
+    eprintln!("{}", failure.message());
+    std::process::exit(failure.exit_code());
+}
+
+impl From<StreamError> for Failure {
+    fn from(e: StreamError) -> Failure {
+        Failure::Stream(e)
+    }
+}
+
+// Command line flags shared by every SRC DST pair
+struct Settings {
+    explicit_type: ArchiveType,
+    block_size: Option<usize>,
+    verbosity: u8,
+    progress: bool,
+    allow_unsafe_paths: bool,
+    threads: usize,
+    recompress: ArchiveType,
+    level: Option<u32>,
+    strip_components: usize,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    transforms: Vec<Transform>,
+    dedup_hardlinks: bool,
+    mmap: bool,
+    keep_comments: bool,
+    decode_layers: usize,
+    resume: bool,
+    preserve_mtime: bool,
+    use_gzip_name: bool,
+    manifest: Option<String>,
+    manifest_json: bool,
+    checksum: Option<String>,
+    recurse: bool,
+    max_size: Option<u64>,
+    head: Option<u64>,
+    ratio_limit: Option<u64>,
+    tar_format: TarFormat,
+    encoding: Option<&'static Encoding>,
+    password: Option<Vec<u8>>,
+    skip_errors: bool,
+    owner: Option<u32>,
+    group: Option<u32>,
+    numeric_owner: bool,
+    file_mode: Option<u32>,
+    dir_mode: Option<u32>,
+    verify_crc: bool,
+    extract: bool,
+    member: Option<String>,
+    force: bool,
+    dereference: bool,
 }
 
 // Print out usage information and exit with specified exit code
 fn usage(code: i32, program: &str, opts: &Options) {
-    let banner = format!("Usage: {} [options] SRC DST", program);
+    let banner = format!("Usage: {} [options] SRC DST [SRC DST ...]\n       {} --list \
+                          [options] SRC\n       {} --dry-run [options] SRC [DST]\n       {} \
+                          --verify-only [options] SRC\n       {} --detect [options] SRC\n       \
+                          {} --extract-to DIR [options] SRC [SRC ...]\n       {} \
+                          --append [options] SRC [SRC ...] DST",
+                         program,
+                         program,
+                         program,
+                         program,
+                         program,
+                         program,
+                         program);
     println!("{} - {}", program, VERSION);
     print!("{}", opts.usage(&banner));
     println!("\nMultiple parameters for the -t / --type argument can be specified\nby \
               separating elements with commas:\n\n    {} --type=gzip,tar some.tar.gz \
-              other.tar",
+              other.tar\n\nThe given types replace detection, except along with auto, \
+              which\ndetects the type and falls back to the other types if that \
+              fails:\n\n    {} --type=auto,tar some.tar other.tar\n\nWithout -t / --type \
+              the types are read from the TAR_STREAMER_TYPE\nenvironment variable if it's \
+              set.\nLikewise file(1) is run as TAR_STREAMER_FILE without \
+              --file-bin.\n\nUse - as SRC to read the archive from standard input and - as DST \
+              to write\nthe result to \
+              standard output:\n\n    cat some.tar.gz | {} - - | tar -t",
+             program,
+             program,
              program);
     std::process::exit(code);
 }
 
-// If haystack contains needle then set a bitflag in flags
-fn find_and_set_flag(haystack: &str, needle: &str, flags: &mut ArchiveType, set: ArchiveType) {
-    if let Some(_) = haystack.find(needle) {
-        *flags |= set;
+// Pick a block size of a sixteenth of the source size, so that small files
+// don't allocate a huge buffer, clamped between MIN_BLOCK_SIZE and
+// DEFAULT_BLOCK_SIZE. Sources of unknown size get DEFAULT_BLOCK_SIZE
+fn default_block_size(src_size: Option<u64>) -> usize {
+    match src_size {
+        Some(size) => {
+            let block = std::cmp::min(size / 16, DEFAULT_BLOCK_SIZE as u64) as usize;
+            std::cmp::max(block, MIN_BLOCK_SIZE)
+        }
+        None => DEFAULT_BLOCK_SIZE,
     }
 }
 
-// Get type of the archive by using the file(1) tool and filename heuristics
-fn get_archive_type(path: &str) -> Option<ArchiveType> {
-    match Command::new("file")
-              .arg(path)
-              .output() {
-        Ok(output) => {
-            let file_output = String::from_utf8_lossy(&output.stdout);
-            let mut typ = INVALID;
+// Largest block size taken from --block-size, bigger ones are capped at it
+// rather than failing the allocation
+const MAX_BLOCK_SIZE: u64 = 1 << 30;
 
-            // Match type identification from the file(1) tool
-            for class in Archives.iter() {
-                find_and_set_flag(&file_output, class.file_fingerprint, &mut typ, class.class);
-            }
-
-            // If there's '.tar' in the file name or the file extension
-            // is .tgz classify the file as Tar
-            find_and_set_flag(&path, ".tar", &mut typ, TAR);
-            find_and_set_flag(&path, ".tgz", &mut typ, TAR);
+// Parse a size in bytes with an optional binary suffix of k, M or G as in
+// 64k or 16M, either case
+fn parse_size(value: &str) -> Option<u64> {
+    let (digits, shift) = match value.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&value[..value.len() - 1], 10),
+        Some('m') => (&value[..value.len() - 1], 20),
+        Some('g') => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
 
-            Some(typ)
-        }
-        Err(_) => None,
-    }
+    digits.parse::<u64>().ok().and_then(|size| size.checked_mul(1 << shift))
 }
 
-// Parse -t / --type parameter from command line
-fn opts_archive_type(typ: &str, verbose: bool) -> ArchiveType {
+// Environment variable with the types to use when --type isn't given
+const TYPE_VARIABLE: &'static str = "TAR_STREAMER_TYPE";
+
+// Environment variable with the file(1) to run when --file-bin isn't given
+const FILE_VARIABLE: &'static str = "TAR_STREAMER_FILE";
+
+// The file(1) that detection runs, set once from --file-bin or FILE_VARIABLE
+static FILE_BIN: Mutex<Option<String>> = Mutex::new(None);
+
+// Parse -t / --type parameter or TAR_STREAMER_TYPE, failing with a message
+// listing the valid names on any unknown one
+fn opts_archive_type(typ: &str) -> Result<ArchiveType, String> {
     // First split the input string by comma, then map each element
     // against the mapping table, yielding an ArchiveType flag, and finally
     // fold the whole sequence with binary OR
-    let parsed = typ.split(",")
-                    .map(|v| {
-                        match Archives.iter()
-                                      .position(|ref p| p.type_name == v.to_ascii_lowercase()) {
-                            Some(index) => Archives[index].class,
-                            None => {
-                                if verbose {
-                                    println!("Invalid --type flag: {}", v);
-                                }
-
-                                INVALID
-                            }
-                        }
-                    })
-                    .fold(INVALID, |acc, x| acc | x);
+    typ.split(",")
+       .map(|v| {
+           if v.eq_ignore_ascii_case("auto") {
+               return Ok(tar_streamer::AUTO);
+           }
 
-    parsed
+           match Archives.iter().position(|ref p| p.type_name == v.to_ascii_lowercase()) {
+               Some(index) => Ok(Archives[index].class),
+               None => {
+                   let names: Vec<&str> = Archives.iter().map(|p| p.type_name).collect();
+                   Err(format!("Invalid type '{}', expected auto or one of {}",
+                               v,
+                               names.join(", ")))
+               }
+           }
+       })
+       .fold(Ok(INVALID), |acc, x| Ok(try!(acc) | try!(x)))
 }
 
-// Write decompressed data from decoder into destination file by using the provided buffer
-fn decode_file_into<T: Chunked>(mut buffer: &mut [u8], dst: &mut File, mut decoder: T) {
-    decoder.chunked(&mut buffer, |buf, read| {
-               if dst.write(&buf[..read]).unwrap_or(0) != read {
-                   error("Unable to write decompressed block");
-               }
-           })
-           .unwrap();
+// Type of the src file as asked for by --type, detected from its contents and
+// name if need be. With auto the other listed types also stand in for
+// file(1) being unavailable
+fn file_type(src: &str,
+             archive_type: ArchiveType,
+             verbose: bool)
+             -> Result<ArchiveType, StreamError> {
+    if archive_type != INVALID && !archive_type.contains(tar_streamer::AUTO) {
+        return Ok(archive_type);
+    }
+
+    let file_bin = FILE_BIN.lock().unwrap().clone().unwrap_or(String::from("file"));
+    let detected = match tar_streamer::get_archive_type_with(src, &file_bin, verbose) {
+        Ok(typ) => typ,
+        Err(StreamError::Detection) if archive_type != tar_streamer::AUTO => INVALID,
+        Err(e) => return Err(e),
+    };
+
+    Ok(tar_streamer::resolve_type(archive_type, detected))
 }
 
-// Stream source file into destination file
-fn stream_file_into(src: &str,
-                    dst: &str,
-                    archive_type: ArchiveType,
-                    block_size: usize,
-                    verbose: bool) {
-    let typ = match archive_type {
-        INVALID => {
-            match get_archive_type(src) {
-                Some(t) => t,
-                None => INVALID,
+// Names of the types set in typ as --type takes them, e.g. "gzip,tar"
+fn type_names(typ: ArchiveType) -> String {
+    typ.to_string().replace(", ", ",")
+}
+
+// Outcome of --detect in JSON form
+#[derive(Serialize)]
+struct Detection {
+    types: Vec<String>,
+    tar_variant: Option<&'static str>,
+}
+
+// Variant of the Tar archive the detected typ of input has, None for other
+// archives. Input that fails to decode just has no variant, detection went
+// by the magic and name
+fn tar_variant<R: Read>(input: R, typ: ArchiveType) -> Option<TarVariant> {
+    match typ.contains(tar_streamer::TAR) {
+        true => tar_streamer::read_tar_variant(input, typ).unwrap_or(None),
+        false => None,
+    }
+}
+
+// Detect the type of src the way converting it would and print it, standard
+// input only has its magic bytes to go by. The Tar variant is told when
+// verbose and in JSON
+fn detect(src: &str,
+          archive_type: ArchiveType,
+          verbose: bool,
+          json: bool)
+          -> Result<(), StreamError> {
+    let (typ, variant) = if src == "-" {
+        let stdin = std::io::stdin();
+        let (typ, input) = try!(tar_streamer::peek_archive_type(stdin.lock()));
+        let typ = tar_streamer::resolve_type(archive_type, typ);
+
+        (typ, tar_variant(input, typ))
+    } else {
+        let typ = try!(file_type(src, archive_type, verbose));
+
+        (typ, tar_variant(try!(File::open(src)), typ))
+    };
+
+    if typ == INVALID {
+        return Err(StreamError::UnknownType(src.to_string()));
+    }
+
+    if let (true, Some(variant)) = (verbose, variant) {
+        eprintln!("Tar variant {}", variant.name());
+    }
+
+    if json {
+        let detection = Detection {
+            types: type_names(typ).split(',').map(|name| name.to_string()).collect(),
+            tar_variant: variant.map(|variant| variant.name()),
+        };
+
+        println!("{}", serde_json::to_string(&detection).unwrap());
+    } else {
+        println!("{}", type_names(typ));
+    }
+
+    Ok(())
+}
+
+// Output is written next to the destination under this name and only moved
+// into place once streaming succeeded, so a failure never truncates DST
+fn partial_path(dst: &str) -> String {
+    format!("{}.partial", dst)
+}
+
+// Open the destination file, dst of "-" writes to stdout, which is held
+// locked for the whole conversion rather than taken again for every write
+fn open_target(dst: &str) -> Result<Box<Write>, StreamError> {
+    match dst {
+        "-" => Ok(Box::new(std::io::stdout().lock())),
+        _ => Ok(Box::new(try!(create_partial(dst)))),
+    }
+}
+
+// Create the partial file of dst afresh. check_target() only lets one that's
+// left over through with force or resume, it's removed rather than truncated
+// so that a symlink put in its place is never written through
+fn create_partial(dst: &str) -> Result<File, StreamError> {
+    let partial = partial_path(dst);
+
+    if let Err(e) = fs::remove_file(&partial) {
+        if e.kind() != ErrorKind::NotFound {
+            return Err(StreamError::from(e));
+        }
+    }
+
+    Ok(try!(fs::OpenOptions::new().write(true).create_new(true).open(&partial)))
+}
+
+// Move the output written by open_target() into place, or throw it away
+// if streaming failed and it isn't kept for resuming. The output is synced
+// to disk first, so that a crash right after the rename can't leave DST
+// with only part of the data
+fn finish_target(dst: &str, succeeded: bool, resume: bool) -> Result<(), StreamError> {
+    if dst == "-" {
+        return Ok(());
+    }
+
+    if succeeded {
+        try!(try!(fs::OpenOptions::new().write(true).open(partial_path(dst))).sync_all());
+        try!(fs::rename(partial_path(dst), dst));
+    } else if !resume {
+        // Nothing was created if the failure happened before open_target()
+        let _ = fs::remove_file(partial_path(dst));
+    }
+
+    Ok(())
+}
+
+// Decide whether dst may be written to before any decoding starts. Existing
+// files are only replaced with force, symlinks are refused likewise and with
+// force the link itself is replaced rather than written through. An extract
+// target has to be a directory, a DST file can't be one. The partial file an
+// earlier run left is only continued with resume or replaced with force, and
+// never when it's a symlink
+fn check_target(dst: &str, extract: bool, force: bool, resume: bool) -> Result<(), String> {
+    if dst == "-" {
+        return Ok(());
+    }
+
+    if !extract {
+        try!(check_partial(&partial_path(dst), force, resume));
+    }
+
+    let meta = match fs::symlink_metadata(dst) {
+        Ok(meta) => meta,
+        Err(_) => return Ok(()),
+    };
+
+    if extract {
+        if !Path::new(dst).is_dir() {
+            return Err(format!("{} is not a directory", dst));
+        }
+    } else if meta.file_type().is_symlink() {
+        if !force {
+            return Err(format!("{} is a symlink, use --force to replace it", dst));
+        }
+    } else if meta.is_dir() {
+        return Err(format!("{} is a directory", dst));
+    } else if !force {
+        return Err(format!("File {} already exists", dst));
+    }
+
+    Ok(())
+}
+
+fn check_partial(partial: &str, force: bool, resume: bool) -> Result<(), String> {
+    let meta = match fs::symlink_metadata(partial) {
+        Ok(meta) => meta,
+        Err(_) => return Ok(()),
+    };
+
+    if meta.file_type().is_symlink() {
+        Err(format!("{} is a symlink, refusing to write through it", partial))
+    } else if meta.is_dir() {
+        Err(format!("{} is a directory", partial))
+    } else if !force && !resume {
+        Err(format!("{} was left by an earlier run, use --resume to continue it or --force \
+                     to start over",
+                    partial))
+    } else {
+        Ok(())
+    }
+}
+
+// Streaming options for src, the block size and progress depend on its size
+fn stream_options(src: &str, settings: &Settings) -> StreamOptions {
+    // The size of stdin isn't known up front
+    let src_size = match src {
+        "-" => None,
+        _ => Path::new(src).metadata().ok().map(|m| m.len()),
+    };
+
+    StreamOptions {
+        block_size: settings.block_size.unwrap_or(default_block_size(src_size)),
+        verbosity: settings.verbosity,
+        allow_unsafe_paths: settings.allow_unsafe_paths,
+        threads: settings.threads,
+        progress: match settings.progress {
+            true => Some(Progress::new(src_size)),
+            false => None,
+        },
+        recompress: settings.recompress,
+        level: settings.level,
+        strip_components: settings.strip_components,
+        include: settings.include.clone(),
+        exclude: settings.exclude.clone(),
+        transforms: settings.transforms.clone(),
+        dedup_hardlinks: settings.dedup_hardlinks,
+        mmap: settings.mmap,
+        keep_comments: settings.keep_comments,
+        decode_layers: settings.decode_layers,
+        manifest: settings.manifest
+            .as_ref()
+            .map(|_| Manifest::new(settings.checksum.as_ref().map(|algorithm| &algorithm[..]))),
+        checksum: settings.checksum.as_ref().and_then(|algorithm| Checksum::new(algorithm)),
+        recurse: settings.recurse,
+        max_size: settings.max_size.map(SizeLimit::new),
+        head: settings.head,
+        ratio_limit: settings.ratio_limit,
+        tar_format: settings.tar_format,
+        encoding: settings.encoding,
+        password: settings.password.clone(),
+        skip_errors: match settings.skip_errors {
+            true => Some(SkippedEntries::new()),
+            false => None,
+        },
+        owner: settings.owner,
+        group: settings.group,
+        numeric_owner: settings.numeric_owner,
+        file_mode: settings.file_mode,
+        dir_mode: settings.dir_mode,
+        verify_crc: settings.verify_crc,
+    }
+}
+
+// First line of the password file at path, without its line ending
+fn read_password(path: &str) -> std::io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+
+    try!(try!(File::open(path)).read_to_end(&mut data));
+
+    let end = data.iter().position(|&b| b == b'\n').unwrap_or(data.len());
+    data.truncate(end);
+    if data.last() == Some(&b'\r') {
+        data.pop();
+    }
+
+    Ok(data)
+}
+
+// Whether src is an HTTP(S) URL to download the archive from
+fn is_url(src: &str) -> bool {
+    src.starts_with("http://") || src.starts_with("https://")
+}
+
+// Path in the dst directory named after the original file the gzip header
+// of src records, dst itself if it isn't a directory
+fn gzip_target(src: &str, dst: &str) -> Result<String, String> {
+    if !Path::new(dst).is_dir() {
+        return Ok(dst.to_string());
+    }
+
+    if src == "-" || is_url(src) {
+        return Err(String::from("--use-gzip-name reads the name from a SRC file"));
+    }
+
+    let file = try!(File::open(src).map_err(|e| format!("{}: {}", src, e)));
+    let header = match tar_streamer::gzip_header(file) {
+        Some(header) => header,
+        None => return Err(format!("{} isn't gzip compressed", src)),
+    };
+
+    // Only the last component, the name mustn't lead out of dst
+    match header.name.as_ref().and_then(|name| Path::new(name).file_name()) {
+        Some(name) => Ok(Path::new(dst).join(name).to_string_lossy().into_owned()),
+        None => Err(format!("{} doesn't record its original name", src)),
+    }
+}
+
+// Suffixes of compressed Tar archives and what's left of them decompressed,
+// longer ones first so .tar.gz isn't taken for .gz. Other archives become
+// Tar archives of the same name
+static SUFFIXES: [(&'static str, &'static str); 29] = [(".tgz", ".tar"),
+                                                       (".taz", ".tar"),
+                                                       (".tbz2", ".tar"),
+                                                       (".tbz", ".tar"),
+                                                       (".txz", ".tar"),
+                                                       (".tzst", ".tar"),
+                                                       (".tlz", ".tar"),
+                                                       (".tZ", ".tar"),
+                                                       (".tzo", ".tar"),
+                                                       (".gz", ""),
+                                                       (".bz2", ""),
+                                                       (".xz", ""),
+                                                       (".zst", ""),
+                                                       (".lz4", ""),
+                                                       (".lzma", ""),
+                                                       (".lz", ""),
+                                                       (".lzo", ""),
+                                                       (".br", ""),
+                                                       (".Z", ""),
+                                                       (".zip", ".tar"),
+                                                       (".jar", ".tar"),
+                                                       (".7z", ".tar"),
+                                                       (".rar", ".tar"),
+                                                       (".cpio", ".tar"),
+                                                       (".img", ".tar"),
+                                                       (".deb", ".tar"),
+                                                       (".ar", ".tar"),
+                                                       (".a", ".tar"),
+                                                       (".tar", ".tar")];
+
+// File name of the Tar archive converted from the file called name
+fn tar_name(name: &str) -> String {
+    for &(suffix, replacement) in SUFFIXES.iter() {
+        if name.len() > suffix.len() && name.ends_with(suffix) {
+            return format!("{}{}", &name[..name.len() - suffix.len()], replacement);
+        }
+    }
+
+    format!("{}.tar", name)
+}
+
+// Path in the dst directory named after src as tar_name() has it, dst
+// itself if it isn't a directory
+fn dir_target(src: &str, dst: &str) -> Result<String, String> {
+    if !Path::new(dst).is_dir() {
+        return Ok(dst.to_string());
+    }
+
+    // The last segment of a URL's path, leaving out the query
+    let name = match src {
+        "-" => None,
+        _ if is_url(src) => src.split(|c| c == '?' || c == '#').next().unwrap().rsplit('/').next(),
+        _ => Path::new(src).file_name().and_then(|name| name.to_str()),
+    };
+
+    match name {
+        Some(name) if !name.is_empty() => {
+            Ok(Path::new(dst).join(tar_name(name)).to_string_lossy().into_owned())
+        }
+        _ => Err(format!("{} is a directory and there's no SRC name to name the output after",
+                         dst)),
+    }
+}
+
+// Give dst the modification time of the archive it was decompressed from,
+// nothing to do for conversions of zip archives and the like or for output
+// that isn't a file
+fn preserve_mtime(src: &str, dst: &str, archive_type: ArchiveType) -> Result<(), StreamError> {
+    if src == "-" || is_url(src) || dst == "-" {
+        return Ok(());
+    }
+
+    // Verbose mode told about the type while converting already
+    let typ = try!(file_type(src, archive_type, false));
+
+    if let Some(mtime) = try!(tar_streamer::source_mtime(&try!(File::open(src)), typ)) {
+        try!(try!(fs::OpenOptions::new().write(true).open(dst)).set_modified(mtime));
+    }
+
+    Ok(())
+}
+
+// Quote a CSV field if it holds a separator, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Write the members recorded by manifest to path, as a JSON array or CSV
+// with a header line. Modes are octal in CSV as in --list, the digest column
+// is empty without --checksum
+fn write_manifest(path: &str, manifest: &Manifest, json: bool) -> Result<(), StreamError> {
+    let mut file = std::io::BufWriter::new(try!(File::create(path)));
+    let entries = manifest.entries();
+
+    if json {
+        try!(serde_json::to_writer(&mut file, &entries).map_err(std::io::Error::from));
+        try!(writeln!(file));
+    } else {
+        try!(writeln!(file, "name,size,mode,digest"));
+
+        for entry in entries.iter() {
+            try!(writeln!(file,
+                          "{},{},{:o},{}",
+                          csv_field(&entry.name),
+                          entry.size,
+                          entry.mode,
+                          entry.digest.as_ref().map_or("", |digest| &digest[..])));
+        }
+    }
+
+    try!(file.flush());
+    Ok(())
+}
+
+// Whether both paths exist and lead to the same file, through symlinks too
+fn same_file(a: &str, b: &str) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+// Fail if writing dst or its partial file would clobber src before it's read
+fn check_distinct(src: &str, dst: &str) -> Result<(), String> {
+    if src == "-" || is_url(src) || dst == "-" {
+        return Ok(());
+    }
+
+    if same_file(src, dst) || same_file(src, &partial_path(dst)) {
+        return Err(format!("{} would be overwritten while it's read, pick another DST", src));
+    }
+
+    Ok(())
+}
+
+// Fail unless src is "-" or a regular file, URLs are only taken by convert().
+// A symlink is followed to its target, which has to be a regular file, unless
+// dereference is off, which refuses it instead. A missing file, a broken
+// symlink too, fails with its own exit code
+fn check_source(src: &str, dereference: bool) -> Result<(), Failure> {
+    if is_url(src) {
+        let message = format!("Only converting reads from URLs, {} can't be used here", src);
+        return Err(Failure::Usage(message));
+    }
+
+    if src == "-" {
+        return Ok(());
+    }
+
+    let not_found = |message: String| {
+        Failure::Stream(StreamError::Io(Error::new(ErrorKind::NotFound, message)))
+    };
+    let is_symlink = match fs::symlink_metadata(src) {
+        Ok(meta) => meta.file_type().is_symlink(),
+        Err(_) => return Err(not_found(format!("File {} not found", src))),
+    };
+
+    if is_symlink && !dereference {
+        return Err(Failure::Usage(format!("{} is a symlink, which --no-dereference refuses",
+                                          src)));
+    }
+
+    match fs::metadata(src) {
+        Ok(ref meta) if meta.is_file() => Ok(()),
+        Ok(_) => Err(Failure::Usage(format!("{} is not a regular file", src))),
+        Err(_) => Err(not_found(format!("{} is a symlink to a missing file", src))),
+    }
+}
+
+// Convert a single SRC DST pair
+fn convert(src: &str, dst: &str, settings: &Settings) -> Result<(), Failure> {
+    if !is_url(src) {
+        try!(check_source(src, settings.dereference));
+    }
+
+    if settings.extract && dst == "-" {
+        return Err(Failure::Usage(String::from("Can't extract into standard output")));
+    }
+
+    // An extraction target is a directory anyway, a DST directory otherwise
+    // gets the output named after SRC
+    let target = if settings.extract {
+        dst.to_string()
+    } else if settings.use_gzip_name {
+        try!(gzip_target(src, dst).map_err(Failure::Usage))
+    } else {
+        try!(dir_target(src, dst).map_err(Failure::Usage))
+    };
+    let dst = &target[..];
+
+    try!(check_distinct(src, dst).map_err(Failure::Usage));
+    try!(check_target(dst, settings.extract, settings.force, settings.resume)
+        .map_err(Failure::Usage));
+
+    let options = stream_options(src, settings);
+    let typ = settings.explicit_type;
+
+    if !settings.extract && !settings.resume && dst != "-" {
+        *IN_PROGRESS.lock().unwrap() = Some(partial_path(dst));
+    }
+
+    let result = match settings.member {
+        Some(ref name) => extract_member_into(src, dst, name, typ, &options),
+        None => stream_file_into(src, dst, typ, settings.extract, settings.resume, &options),
+    };
+
+    if let Some(ref progress) = options.progress {
+        progress.finish();
+    }
+
+    if !settings.extract {
+        let finished = finish_target(dst, result.is_ok(), settings.resume);
+
+        // Moved into place or thrown away, there's nothing left to clean up
+        *IN_PROGRESS.lock().unwrap() = None;
+        try!(finished);
+    }
+
+    try!(result);
+
+    if settings.preserve_mtime && !settings.extract && settings.member.is_none() {
+        try!(preserve_mtime(src, dst, typ).map_err(Failure::Stream));
+    }
+
+    if let Some(ref path) = settings.manifest {
+        let manifest = options.manifest.as_ref().unwrap();
+
+        try!(write_manifest(path, manifest, settings.manifest_json).map_err(Failure::Stream));
+    }
+
+    // Laid out like sha256sum and friends, stdout may be the output itself
+    if let Some(ref checksum) = options.checksum {
+        eprintln!("{}  {}", checksum.hex_digest(), dst);
+    }
+
+    if let Some(ref skipped) = options.skip_errors {
+        let entries = skipped.entries();
+
+        if !entries.is_empty() {
+            eprintln!("Skipped {} members with errors:", entries.len());
+            for entry in &entries {
+                eprintln!("    {}: {}", entry.name, entry.reason);
             }
+
+            return Err(Failure::Incomplete(entries.len()));
+        }
+    }
+
+    Ok(())
+}
+
+// Append the members of every one of srcs to the Tar archive dst, each SRC
+// is converted with the shared flags and the output gets recompressed,
+// hashed and listed in the manifest as a whole
+fn append_into(srcs: &[String],
+               dst: &str,
+               collision: Collision,
+               settings: &Settings)
+               -> Result<(), Failure> {
+    for src in srcs {
+        if src == "-" {
+            return Err(Failure::Usage(String::from("--append can't read standard input")));
         }
-        _ => archive_type,
+
+        try!(check_source(src, settings.dereference));
+        try!(check_distinct(src, dst).map_err(Failure::Usage));
+    }
+
+    try!(check_target(dst, false, settings.force, false).map_err(Failure::Usage));
+
+    let mut sources = Vec::new();
+
+    for src in srcs {
+        let file = try!(File::open(src).map_err(StreamError::from));
+        let typ = try!(file_type(src, settings.explicit_type, settings.verbosity > 0));
+        let mut options = stream_options(src, settings);
+
+        if typ == INVALID {
+            return Err(Failure::Stream(StreamError::UnknownType(src.to_string())));
+        }
+
+        // The unrar library opens the archive by its path
+        if typ.contains(tar_streamer::RAR) {
+            return Err(Failure::Stream(StreamError::UnsupportedType(typ)));
+        }
+
+        // Compressing, hashing and listing are done on the output
+        options.recompress = INVALID;
+        options.checksum = None;
+        options.manifest = None;
+        sources.push((file, typ, options));
+    }
+
+    // Progress and skipped members are reported for every SRC
+    let mut options = stream_options(dst, settings);
+    options.progress = None;
+    options.skip_errors = None;
+
+    if dst != "-" {
+        *IN_PROGRESS.lock().unwrap() = Some(partial_path(dst));
+    }
+
+    let appended: Vec<_> = sources.iter()
+        .map(|&(ref file, typ, ref options)| (file, typ, options))
+        .collect();
+    let result = open_target(dst).and_then(|target| {
+        tar_streamer::append_files(&appended, target, collision, &options)
+    });
+
+    for &(_, _, ref source_options) in &sources {
+        if let Some(ref progress) = source_options.progress {
+            progress.finish();
+        }
+    }
+
+    let finished = finish_target(dst, result.is_ok(), false);
+    *IN_PROGRESS.lock().unwrap() = None;
+    try!(finished);
+    try!(result);
+
+    if let Some(ref path) = settings.manifest {
+        let manifest = options.manifest.as_ref().unwrap();
+
+        try!(write_manifest(path, manifest, settings.manifest_json).map_err(Failure::Stream));
+    }
+
+    if let Some(ref checksum) = options.checksum {
+        eprintln!("{}  {}", checksum.hex_digest(), dst);
+    }
+
+    let mut skipped = 0;
+
+    for (src, &(_, _, ref source_options)) in srcs.iter().zip(&sources) {
+        let entries = source_options.skip_errors.as_ref().map_or(Vec::new(), |s| s.entries());
+
+        if !entries.is_empty() {
+            eprintln!("Skipped {} members of {} with errors:", entries.len(), src);
+            for entry in &entries {
+                eprintln!("    {}: {}", entry.name, entry.reason);
+            }
+
+            skipped += entries.len();
+        }
+    }
+
+    match skipped {
+        0 => Ok(()),
+        count => Err(Failure::Incomplete(count)),
+    }
+}
+
+// Totals of the members printed by --list-with-sizes, the compressed size
+// and its ratio to the size are only known for zip archives
+#[derive(Serialize, Default)]
+struct Summary {
+    entries: u64,
+    size: u64,
+    compressed_size: Option<u64>,
+    ratio: Option<f64>,
+}
+
+impl Summary {
+    fn add(&mut self, entry: &tar_streamer::ListEntry) {
+        self.entries += 1;
+        self.size += entry.size;
+
+        if let Some(compressed) = entry.compressed_size {
+            self.compressed_size = Some(self.compressed_size.unwrap_or(0) + compressed);
+        }
+
+        self.ratio = match (self.compressed_size, self.size) {
+            (Some(compressed), size) if size > 0 => Some(compressed as f64 / size as f64),
+            _ => None,
+        };
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let noun = if self.entries == 1 { "entry" } else { "entries" };
+
+        try!(write!(f, "{} {}, {} bytes", self.entries, noun, self.size));
+
+        match (self.compressed_size, self.ratio) {
+            (Some(compressed), Some(ratio)) => {
+                write!(f, ", {} compressed ({:.1}%)", compressed, ratio * 100.0)
+            }
+            (Some(compressed), None) => write!(f, ", {} compressed", compressed),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Print the members of the source archive, src of "-" reads from stdin. With
+// json the members make up a JSON array, printed one per line as they're
+// read so large archives aren't held in memory. With sizes a summary line
+// follows, in JSON the array and the summary go into an object
+fn list_file(src: &str,
+             archive_type: ArchiveType,
+             options: &StreamOptions,
+             json: bool,
+             sizes: bool)
+             -> Result<(), StreamError> {
+    let mut first = true;
+    let mut summary = Summary::default();
+    let open = if sizes { "{\"entries\":[" } else { "[" };
+    let print = |entry: &tar_streamer::ListEntry| {
+        summary.add(entry);
+
+        if json {
+            println!("{}{}", if first { open } else { "," }, serde_json::to_string(entry).unwrap());
+            first = false;
+        } else {
+            println!("{:06o} {:>12} {}", entry.mode, entry.size, entry.name);
+        }
+    };
+
+    let result = if src == "-" {
+        let stdin = std::io::stdin();
+        tar_streamer::list(stdin.lock(), archive_type, options, print)
+    } else {
+        let file = try!(File::open(src));
+        let typ = try!(file_type(src, archive_type, options.verbose()));
+
+        tar_streamer::list_file(&file, typ, options, print)
     };
-    let mut target = File::create(dst).unwrap();
-    let mut buffer: Vec<u8> = vec!(0u8; block_size);
-    let file = File::open(src).unwrap();
 
-    if typ.contains(GZIP) {
-        if verbose {
-            println!("GZip file");
+    if result.is_err() {
+        return result;
+    }
+
+    // Close the array, or print an empty one for an archive without members
+    if json {
+        let array = if first { format!("{}]", open) } else { String::from("]") };
+
+        if sizes {
+            println!("{},\"summary\":{}}}", array, serde_json::to_string(&summary).unwrap());
+        } else {
+            println!("{}", array);
         }
+    } else if sizes {
+        println!("{}", summary);
+    }
+
+    Ok(())
+}
+
+// Writer throwing the data away, only counting how much there was
+struct Counter {
+    bytes: u64,
+}
+
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.bytes += buf.len() as u64;
+        Ok(buf.len())
+    }
 
-        let decoder = GzDecoder::new(file).unwrap();
-        decode_file_into(&mut buffer, &mut target, decoder);
-    } else if typ.contains(BZIP2) {
-        if verbose {
-            println!("BZip2 file");
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Run detection and report what converting src would do without writing
+// anything. Zip members are only enumerated, everything else is decoded in
+// full to count the output size
+fn dry_run(src: &str,
+           dst: Option<&str>,
+           archive_type: ArchiveType,
+           options: &StreamOptions)
+           -> Result<(), StreamError> {
+    let stdin = std::io::stdin();
+    let mut members = 0;
+    let mut size = 0;
+    let mut counter = Counter { bytes: 0 };
+
+    let typ = if src == "-" {
+        let (typ, input) = try!(tar_streamer::peek_archive_type(stdin.lock()));
+        let typ = tar_streamer::resolve_type(archive_type, typ);
+
+        if typ.contains(tar_streamer::ZIP) {
+            try!(tar_streamer::list(input, typ, options, |entry| {
+                members += 1;
+                size += entry.size;
+            }));
+        } else {
+            try!(tar_streamer::stream_with_options(input, &mut counter, typ, options));
         }
 
-        let decoder = BzDecoder::new(file);
-        decode_file_into(&mut buffer, &mut target, decoder);
-    } else if typ.contains(XZ) {
-        if verbose {
-            println!("XZ file");
+        typ
+    } else {
+        let file = try!(File::open(src));
+        let typ = try!(file_type(src, archive_type, options.verbose()));
+
+        if typ.contains(tar_streamer::ZIP) {
+            try!(tar_streamer::list_file(&file, typ, options, |entry| {
+                members += 1;
+                size += entry.size;
+            }));
+        } else {
+            try!(tar_streamer::stream_file(&file, &mut counter, typ, options));
         }
 
-        let decoder = XzDecoder::new(file);
-        decode_file_into(&mut buffer, &mut target, decoder);
-    } else if typ.contains(ZIP) {
-        if verbose {
-            println!("Zip file");
+        typ
+    };
+
+    let target = dst.unwrap_or("DST");
+
+    if typ.contains(tar_streamer::ZIP) {
+        println!("{}: {}, {} members with {} bytes uncompressed would be converted into a \
+                  Tar archive in {}",
+                 src,
+                 typ,
+                 members,
+                 size,
+                 target);
+    } else {
+        println!("{}: {}, {} bytes would be written to {}",
+                 src,
+                 typ,
+                 counter.bytes,
+                 target);
+    }
+
+    Ok(())
+}
+
+// Decode all of src, zip members included, so that every checksum along the
+// way gets checked, and throw the output away
+fn verify_only(src: &str,
+               archive_type: ArchiveType,
+               options: &StreamOptions)
+               -> Result<(), StreamError> {
+    let mut counter = Counter { bytes: 0 };
+
+    if src == "-" {
+        let stdin = std::io::stdin();
+        try!(tar_streamer::stream_with_options(stdin.lock(), &mut counter, archive_type, options));
+    } else {
+        let file = try!(File::open(src));
+        let typ = try!(file_type(src, archive_type, options.verbose()));
+
+        if typ.contains(tar_streamer::RAR) {
+            try!(tar_streamer::stream_rar(src, &mut counter, options));
+        } else {
+            try!(tar_streamer::stream_file(&file, &mut counter, typ, options));
         }
+    }
 
-        let file_meta = file.metadata().unwrap();
-        let mut decoder = ZipArchive::new(&file).unwrap();
-        let mut tar_builder = Builder::new(target);
+    println!("{}: OK, {} bytes decoded", src, counter.bytes);
 
-        for i in 0..decoder.len() {
-            // Get hold of ZipFile at particular index
-            let zf = decoder.by_index(i).unwrap();
+    if let Some(ref checksum) = options.checksum {
+        eprintln!("{}  {}", checksum.hex_digest(), src);
+    }
 
-            // Create a Tar header for each ZipFile
-            let mut tar_header = Header::new_gnu();
+    Ok(())
+}
 
-            // Set file metadata in tar header
-            tar_header.set_size(zf.size());
-            tar_header.set_path(Path::new(zf.name())).unwrap();
-            tar_header.set_mode(zf.unix_mode().unwrap());
-            tar_header.set_mtime(file_meta.mtime() as u64);
-            tar_header.set_uid(file_meta.uid());
-            tar_header.set_gid(file_meta.gid());
-            tar_header.set_cksum();
+// Write the named member of the source archive into the destination file,
+// src of "-" reads from stdin and dst of "-" writes to stdout
+fn extract_member_into(src: &str,
+                       dst: &str,
+                       name: &str,
+                       archive_type: ArchiveType,
+                       options: &StreamOptions)
+                       -> Result<(), StreamError> {
+    if src == "-" {
+        let stdin = std::io::stdin();
+        tar_streamer::extract_member(stdin.lock(),
+                                     try!(open_target(dst)),
+                                     name,
+                                     archive_type,
+                                     options)
+    } else {
+        let file = try!(File::open(src));
+        let typ = try!(file_type(src, archive_type, options.verbose()));
+
+        tar_streamer::extract_member_file(&file, try!(open_target(dst)), name, typ, options)
+    }
+}
+
+// Stream source file into destination file, src of "-" reads from stdin
+// and dst of "-" writes to stdout. With extract set dst is a directory
+// the Tar archive gets unpacked into
+fn stream_file_into(src: &str,
+                    dst: &str,
+                    archive_type: ArchiveType,
+                    extract: bool,
+                    resume: bool,
+                    options: &StreamOptions)
+                    -> Result<(), StreamError> {
+    if src == "-" {
+        let stdin = std::io::stdin();
+        stream_reader_into(stdin.lock(), src, dst, archive_type, extract, options)
+    } else if is_url(src) {
+        stream_reader_into(try!(open_url(src)), src, dst, archive_type, extract, options)
+    } else {
+        let file = try!(File::open(src));
+        let typ = try!(file_type(src, archive_type, options.verbose()));
 
-            tar_builder.append(&tar_header, zf).unwrap();
+        if typ == INVALID {
+            return Err(StreamError::UnknownType(src.to_string()));
         }
 
-        tar_builder.finish().unwrap();
-    } else if typ.contains(TAR) {
-        if verbose {
-            println!("Tar file");
+        if extract {
+            tar_streamer::extract_file(&file, dst, typ, options)
+        } else if typ.contains(tar_streamer::RAR) {
+            // The unrar library opens the archive by its path
+            tar_streamer::stream_rar(src, try!(open_target(dst)), options)
+        } else if dst == "-" {
+            tar_streamer::stream_file(&file, try!(open_target(dst)), typ, options)
+        } else if resume {
+            // Keep whatever an earlier, interrupted run left behind
+            let mut target = try!(fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .open(partial_path(dst)));
+            tar_streamer::resume_file_to_file(&file, &mut target, typ, options)
+        } else {
+            let mut target = try!(create_partial(dst));
+            tar_streamer::stream_file_to_file(&file, &mut target, typ, options)
         }
+    }
+}
+
+// Same as stream_file_into() for sources that aren't files, stdin or the
+// body of an HTTP(S) response. There's no path to run file(1) on, so the
+// magic is sniffed from their head
+fn stream_reader_into<R: Read>(src: R,
+                               name: &str,
+                               dst: &str,
+                               archive_type: ArchiveType,
+                               extract: bool,
+                               options: &StreamOptions)
+                               -> Result<(), StreamError> {
+    let (typ, input) = try!(tar_streamer::peek_archive_type(src));
+    let typ = tar_streamer::resolve_type(archive_type, typ);
+
+    if input.get_ref().0.get_ref().is_empty() {
+        return Err(StreamError::Empty);
+    }
+
+    if typ == INVALID {
+        return Err(StreamError::UnknownType(name.to_string()));
+    }
 
-        decode_file_into(&mut buffer, &mut target, &file);
+    if extract {
+        tar_streamer::extract(input, dst, typ, options)
     } else {
-        error(&format!("Unknown file type '{:?}' for '{}'", typ, src));
+        tar_streamer::stream_with_options(input, try!(open_target(dst)), typ, options)
     }
 }
 
+// Body of the response to a GET request for url, read as it arrives. The
+// client's default timeout covers the whole body, which large archives
+// take longer than, so there is none
+#[cfg(feature = "net")]
+fn open_url(url: &str) -> Result<reqwest::blocking::Response, StreamError> {
+    let request = reqwest::blocking::Client::builder()
+        .timeout(None)
+        .build()
+        .and_then(|client| client.get(url).send())
+        .and_then(|response| response.error_for_status());
+
+    // The errors of reqwest name the URL themselves
+    request.map_err(|e| StreamError::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))
+}
+
+#[cfg(not(feature = "net"))]
+fn open_url(_: &str) -> Result<std::io::Empty, StreamError> {
+    Err(StreamError::Io(std::io::Error::new(std::io::ErrorKind::Other,
+                                            "Reading from URLs needs a build with the net \
+                                             feature")))
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     let program = args[0].split('/').last().unwrap();
 
     let mut opts = Options::new();
     opts.optflag("h", "help", "prints this menu");
-    opts.optflag("v", "verbose", "verbose mode");
+    opts.optflagmulti("v",
+                      "verbose",
+                      "verbose mode, twice to trace every block and converted member");
+    opts.optflag("p", "progress", "report progress to stderr");
     opts.optflag("f", "force", "overwrite existing files");
+    opts.optflag("", "no-clobber", "never overwrite existing files, the default");
+    opts.optflag("", "dereference", "read symlinked SRC files from their target, the default");
+    opts.optflag("", "no-dereference", "refuse SRC files that are symlinks");
+    opts.optflag("x", "extract", "unpack the archive into the DST directory");
+    opts.optflag("",
+                 "use-gzip-name",
+                 "write into DST/NAME when DST is a directory, NAME being the original \
+                  file name stored in the gzip header of SRC");
+    opts.optflag("",
+                 "preserve-mtime",
+                 "give DST the modification time of SRC, or of the original file for \
+                  gzip, unless SRC is converted member by member");
+    opts.optflag("",
+                 "resume",
+                 "continue from the DST.partial file an interrupted run left, keeping it \
+                  if this one fails too");
+    opts.optflag("",
+                 "append",
+                 "convert every SRC into one Tar archive written to DST, the last argument");
+    opts.optopt("",
+                "on-collision",
+                "what --append does with a member named like one of an earlier SRC: skip it, \
+                 rename it to NAME.1 and so on, or error out, the default",
+                "[SKIP, RENAME, ERROR]");
+    opts.optopt("",
+                "extract-to",
+                "unpack every SRC into DIR, no DST arguments are taken",
+                "DIR");
+    opts.optflag("l", "list", "print the archive members instead of converting");
+    opts.optflag("",
+                 "list-with-sizes",
+                 "same as --list followed by the number of members, their total size and \
+                  for zips their compressed size");
+    opts.optflag("n",
+                 "dry-run",
+                 "detect SRC and report what would be written without creating DST");
+    opts.optflag("",
+                 "verify-only",
+                 "decode SRC in full to check its integrity without writing anything");
+    opts.optflag("",
+                 "detect",
+                 "print the detected type(s) of SRC, failing if there are none");
+    opts.optflag("",
+                 "json",
+                 "print the output of --list or --detect as JSON");
+    opts.optopt("",
+                "member",
+                "write just the contents of the named member into DST",
+                "NAME");
+    opts.optopt("",
+                "strip-components",
+                "drop the first N path components of zip members and of extracted \
+                 members",
+                "N");
+    opts.optopt("",
+                "owner",
+                "give every member of the Tar archive the numeric UID as its owner",
+                "UID");
+    opts.optopt("",
+                "group",
+                "give every member of the Tar archive the numeric GID as its group",
+                "GID");
+    opts.optflag("",
+                 "numeric-owner",
+                 "leave user and group names out of the Tar archive, keeping the ids");
+    opts.optopt("",
+                "mode",
+                "give every regular file of the Tar archive the octal permissions MODE",
+                "MODE");
+    opts.optopt("",
+                "dir-mode",
+                "give every directory of the Tar archive the octal permissions MODE",
+                "MODE");
+    opts.optmulti("",
+                  "include",
+                  "only convert or extract members matching the glob PATTERN, can be \
+                   repeated",
+                  "PATTERN");
+    opts.optmulti("",
+                  "exclude",
+                  "leave out members matching the glob PATTERN, wins over --include, can \
+                   be repeated",
+                  "PATTERN");
+    opts.optmulti("",
+                  "transform",
+                  "rewrite the paths of converted members with a sed-like \
+                   s/REGEX/REPLACEMENT/[gi] expression, can be repeated",
+                  "EXPRESSION");
+    opts.optflag("",
+                 "allow-unsafe-paths",
+                 "keep absolute and '..' member paths when converting zips");
+    opts.optflag("",
+                 "skip-errors",
+                 "leave out zip members that can't be read instead of giving up, checking \
+                  each one first, and exit with 15 if any were left out");
+    opts.optflag("",
+                 "verify-crc",
+                 "recompute the CRC-32 of every zip member and name the member and both \
+                  CRC-32s when they don't match");
+    opts.optopt("",
+                "encoding",
+                "decode zip member names without the UTF-8 flag from this encoding, such \
+                 as SHIFT_JIS or GBK, instead of CP437",
+                "NAME");
+    opts.optopt("",
+                "password",
+                "decrypt ZipCrypto and AES encrypted zip members with PASS, which other \
+                 users can see in the process list",
+                "PASS");
+    opts.optopt("",
+                "password-file",
+                "read the password from the first line of FILE instead",
+                "FILE");
+    opts.optflag("",
+                 "dedup-hardlinks",
+                 "write zip members with the same contents as an earlier one as hard \
+                  links to it, hashing the candidates");
+    opts.optflag("",
+                 "keep-comments",
+                 "store zip archive and member comments in PAX records, which makes the \
+                  output larger");
+    opts.optflag("",
+                 "recurse",
+                 "convert the Tar archives inside an ar archive, such as the parts of a .deb, \
+                  into their members");
     opts.optopt("t",
                 "type",
                 "input archive type(s)",
-                "[GZIP, ZIP, BZIP2, XZ, TAR]");
-    opts.optopt("b", "block-size", "size of processing block in bytes", "");
+                "[AUTO, GZIP, ZIP, BZIP2, XZ, LZMA, LZIP, LZOP, ZSTD, LZ4, COMPRESS, \
+                 BROTLI, RAR, 7Z, CPIO, AR, TAR]");
+    opts.optopt("",
+                "to",
+                "compress the output Tar archive",
+                "[GZIP, BZIP2, XZ, ZSTD]");
+    opts.optopt("",
+                "tar-format",
+                "header layout of Tar archives converted from other formats, defaults to \
+                 GNU",
+                "[GNU, PAX, USTAR]");
+    opts.optopt("",
+                "manifest",
+                "write the name, size, mode and, with --checksum, digest of every member of \
+                 the Tar archive to FILE",
+                "FILE");
+    opts.optopt("",
+                "manifest-format",
+                "format of the --manifest FILE, defaults to JSON for a .json FILE and CSV \
+                 otherwise",
+                "[CSV, JSON]");
+    opts.optopt("",
+                "checksum",
+                "print the digest of the Tar archive or decompressed stream, before \
+                 --to compression, to stderr",
+                "[SHA256, SHA1, MD5]");
+    opts.optopt("",
+                "level",
+                "compression level for --to, 0-9 for GZIP and XZ, 1-9 for BZIP2 and 1-22 \
+                 for ZSTD",
+                "N");
+    opts.optopt("",
+                "max-size",
+                "give up once more than BYTES have been decompressed from a SRC, \
+                 recommended for untrusted input",
+                "BYTES");
+    opts.optopt("",
+                "bytes",
+                "write just the first N bytes of the decompressed stream, with an optional k, \
+                 M or G suffix, and stop decompressing there",
+                "N");
+    opts.optopt("",
+                "ratio-limit",
+                "give up once compressed data or a zip member decompresses to more than N \
+                 times its size",
+                "N");
+    opts.optopt("b",
+                "block-size",
+                "size of processing block in bytes or with a k, M or G suffix, at most 1G, \
+                 defaults to a sixteenth of the SRC size within 64KiB and 16MiB",
+                "SIZE");
+    opts.optflag("",
+                 "mmap",
+                 "read SRC files through a memory map instead of read calls");
+    opts.optopt("",
+                "threads",
+                "decompress zip members, or the blocks of xz files in parallel-xz builds, in \
+                 N threads, buffering up to two zip members of at most 32MiB per thread in \
+                 memory",
+                "N");
+    opts.optopt("",
+                "decode-layers",
+                "decompress up to N nested compression layers, such as the two of \
+                 .tar.gz.gz, defaults to 1",
+                "N");
+    opts.optopt("",
+                "file-bin",
+                "run PATH instead of file(1) for the inputs whose magic bytes aren't \
+                 recognized",
+                "PATH");
     opts.optflag("", "version", "display version information");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
-        Err(e) => panic!(e.to_string()),
+        Err(e) => error(&format!("{}, see --help", e)),
     };
 
     if matches.opt_present("h") {
@@ -284,39 +1433,494 @@ fn main() {
         usage(0, &program, &opts);
     }
 
-    if matches.free.len() != 2 {
-        usage(1, &program, &opts);
-    } else {
-        let src = &matches.free[0];
-        let dst = &matches.free[1];
+    *FILE_BIN.lock().unwrap() = matches.opt_str("file-bin")
+        .or_else(|| env::var(FILE_VARIABLE).ok().filter(|path| !path.is_empty()));
+
+    let sizes = matches.opt_present("list-with-sizes");
+    let list = matches.opt_present("l") || sizes;
+    let extract_to = matches.opt_str("extract-to");
+    let dry = matches.opt_present("n");
+    let verify = matches.opt_present("verify-only");
+    let detect_only = matches.opt_present("detect");
+    let append = matches.opt_present("append");
+    let collision = match matches.opt_str("on-collision") {
+        Some(value) => {
+            match Collision::from_name(&value) {
+                Some(collision) => collision,
+                None => error(&format!("Invalid collision policy: {}", value)),
+            }
+        }
+        None => Collision::Error,
+    };
+
+    if !append && matches.opt_present("on-collision") {
+        error("--on-collision needs --append");
+    }
+
+    if append && (list || dry || verify || detect_only || extract_to.is_some()) {
+        error("--append can't be combined with --list, --dry-run, --verify-only, --detect or \
+               --extract-to");
+    }
+
+    if list && extract_to.is_some() {
+        error("--list and --extract-to can't be combined");
+    }
+
+    if verify && (list || dry || extract_to.is_some()) {
+        error("--verify-only can't be combined with --list, --dry-run or --extract-to");
+    }
+
+    if detect_only && (list || dry || verify || extract_to.is_some()) {
+        error("--detect can't be combined with --list, --dry-run, --verify-only or --extract-to");
+    }
+
+    let json = matches.opt_present("json");
+
+    if json && !list && !detect_only {
+        error("--json only applies to --list and --detect");
+    }
+
+    let manifest = matches.opt_str("manifest");
+    let manifest_json = match matches.opt_str("manifest-format") {
+        Some(ref value) if value.eq_ignore_ascii_case("json") => true,
+        Some(ref value) if value.eq_ignore_ascii_case("csv") => false,
+        Some(value) => error(&format!("Invalid manifest format: {}", value)),
+        None => manifest.as_ref().map_or(false, |path| path.ends_with(".json")),
+    };
+
+    if manifest.is_none() && matches.opt_present("manifest-format") {
+        error("--manifest-format needs --manifest");
+    }
+
+    if manifest.is_some() && (list || dry || verify || detect_only || extract_to.is_some()) {
+        error("--manifest only applies when converting into a Tar archive");
+    }
+
+    // With --extract-to every SRC gets unpacked into the same directory,
+    // which makes it the DST of every pair
+    let free = match extract_to {
+        Some(ref dir) => {
+            matches.free.iter().flat_map(|src| vec![src.clone(), dir.clone()]).collect()
+        }
+        None => matches.free.clone(),
+    };
+
+    // Listing, verifying and detecting only read the source so there's no DST
+    // argument, a dry run may be given one for show, appending takes any number
+    // of SRCs before DST, otherwise SRC and DST alternate
+    if (list || verify || detect_only) && free.len() != 1 ||
+       dry && (free.is_empty() || free.len() > 2) || append && free.len() < 2 ||
+       !list && !dry && !verify && !detect_only && !append &&
+       (free.is_empty() || free.len() % 2 != 0) {
+        usage(USAGE, &program, &opts);
+    }
+
+    let verbosity = std::cmp::min(matches.opt_count("v"), u8::max_value() as usize) as u8;
+    let block_size = match matches.opt_str("b") {
+        Some(value) => {
+            match parse_size(&value) {
+                Some(0) => error("Invalid block size: 0, it has to be at least one byte"),
+                Some(size) if size > MAX_BLOCK_SIZE => {
+                    eprintln!("Warning: block size {} capped at 1G", value);
+                    Some(MAX_BLOCK_SIZE as usize)
+                }
+                Some(size) => Some(size as usize),
+                None => error(&format!("Invalid block size: {}", value)),
+            }
+        }
+        None => None,
+    };
+    let max_size = match matches.opt_str("max-size") {
+        Some(value) => {
+            match value.parse::<u64>() {
+                Ok(int) => Some(int),
+                Err(_) => error(&format!("Invalid maximum size: {}", value)),
+            }
+        }
+        None => None,
+    };
+    let head = matches.opt_str("bytes").map(|value| {
+        match parse_size(&value) {
+            Some(size) => size,
+            None => error(&format!("Invalid number of bytes: {}", value)),
+        }
+    });
+    let ratio_limit = match matches.opt_str("ratio-limit") {
+        Some(value) => {
+            match value.parse::<u64>() {
+                Ok(int) if int > 0 => Some(int),
+                _ => error(&format!("Invalid ratio limit: {}", value)),
+            }
+        }
+        None => None,
+    };
+    let threads = match matches.opt_str("threads") {
+        Some(value) => {
+            match value.parse::<usize>() {
+                Ok(int) if int > 0 => int,
+                _ => error(&format!("Invalid number of threads: {}", value)),
+            }
+        }
+        None => 1,
+    };
+    let decode_layers = match matches.opt_str("decode-layers") {
+        Some(value) => {
+            match value.parse::<usize>() {
+                Ok(int) if int > 0 => int,
+                _ => error(&format!("Invalid number of layers: {}", value)),
+            }
+        }
+        None => 1,
+    };
+    let strip_components = match matches.opt_str("strip-components") {
+        Some(value) => {
+            match value.parse::<usize>() {
+                Ok(int) => int,
+                _ => error(&format!("Invalid number of components: {}", value)),
+            }
+        }
+        None => 0,
+    };
+    let id = |name: &str| -> Option<u32> {
+        matches.opt_str(name).map(|value| {
+            match value.parse::<u32>() {
+                Ok(id) => id,
+                _ => error(&format!("Invalid --{} {}, it has to be a numeric id", name, value)),
+            }
+        })
+    };
+    let mode = |name: &str| -> Option<u32> {
+        matches.opt_str(name).map(|value| {
+            match u32::from_str_radix(&value, 8) {
+                Ok(mode) if mode <= 0o7777 => mode,
+                _ => error(&format!("Invalid --{} {}, it has to be octal like 0644", name, value)),
+            }
+        })
+    };
+    let patterns = |name: &str| -> Vec<Pattern> {
+        matches.opt_strs(name)
+            .iter()
+            .map(|value| {
+                match Pattern::new(value) {
+                    Ok(pattern) => pattern,
+                    Err(e) => error(&format!("Invalid pattern {}: {}", value, e)),
+                }
+            })
+            .collect()
+    };
+    let checksum = matches.opt_str("checksum");
 
-        let src_path = Path::new(src);
-        let dst_path = Path::new(dst);
+    if let Some(ref algorithm) = checksum {
+        if Checksum::new(algorithm).is_none() {
+            error(&format!("Invalid checksum algorithm: {}", algorithm));
+        }
+    }
 
-        if !src_path.exists() || !src_path.is_file() {
-            error(&format!("File {} not found", src));
+    let tar_format = match matches.opt_str("tar-format") {
+        Some(value) => {
+            match TarFormat::from_name(&value) {
+                Some(format) => format,
+                None => error(&format!("Invalid Tar format: {}", value)),
+            }
         }
+        None => TarFormat::Gnu,
+    };
 
-        if dst_path.exists() && !matches.opt_present("f") {
-            error(&format!("File {} already exists", dst));
+    if tar_format == TarFormat::Ustar && matches.opt_present("keep-comments") {
+        error("--keep-comments needs PAX records, which --tar-format ustar leaves out");
+    }
+    let encoding = match matches.opt_str("encoding") {
+        // CP437 is the zip crate's own fallback, encoding_rs doesn't have it
+        Some(ref value) if value.eq_ignore_ascii_case("cp437") ||
+                           value.eq_ignore_ascii_case("ibm437") => None,
+        Some(value) => {
+            match Encoding::for_label(value.as_bytes()) {
+                Some(encoding) => Some(encoding),
+                None => error(&format!("Invalid encoding: {}", value)),
+            }
+        }
+        None => None,
+    };
+    let password = match (matches.opt_str("password"), matches.opt_str("password-file")) {
+        (Some(_), Some(_)) => error("--password and --password-file can't be combined"),
+        (Some(password), None) => Some(password.into_bytes()),
+        (None, Some(path)) => {
+            match read_password(&path) {
+                Ok(password) => Some(password),
+                Err(e) => error(&format!("Unable to read password file {}: {}", path, e)),
+            }
         }
+        (None, None) => None,
+    };
+    let recompress = match matches.opt_str("to") {
+        Some(value) => {
+            match opts_archive_type(&value) {
+                Ok(typ @ tar_streamer::GZIP) |
+                Ok(typ @ tar_streamer::BZIP2) |
+                Ok(typ @ tar_streamer::XZ) |
+                Ok(typ @ tar_streamer::ZSTD) => typ,
+                _ => error(&format!("Invalid --to format: {}", value)),
+            }
+        }
+        None => INVALID,
+    };
+    let level = match matches.opt_str("level") {
+        Some(value) => {
+            let (min, max, _) = match tar_streamer::compression_levels(recompress) {
+                Some(levels) => levels,
+                None => error("--level requires --to"),
+            };
 
-        let verbose = matches.opt_present("v");
-        let explicit_type = match matches.opt_str("t") {
-            Some(value) => opts_archive_type(&value, verbose),
-            None => INVALID,
-        };
-        let block_size = match matches.opt_str("b") {
+            match value.parse::<u32>() {
+                Ok(int) if int >= min && int <= max => Some(int),
+                _ => {
+                    error(&format!("Invalid compression level {} for {}, expected {}-{}",
+                                   value,
+                                   recompress,
+                                   min,
+                                   max))
+                }
+            }
+        }
+        None => None,
+    };
+    let settings = Settings {
+        explicit_type: match matches.opt_str("t") {
             Some(value) => {
-                match value.parse::<usize>() {
-                    Ok(int) => int,
-                    Err(_) => panic!(format!("Invalid block size: {}", value)),
+                match opts_archive_type(&value) {
+                    Ok(typ) => typ,
+                    Err(message) => error(&message),
                 }
             }
-            None => 1 << 24, // default to 16mb blocks
-        };
+            None => {
+                match env::var(TYPE_VARIABLE) {
+                    Ok(ref value) if !value.is_empty() => {
+                        match opts_archive_type(value) {
+                            Ok(typ) => typ,
+                            Err(message) => error(&format!("{}: {}", TYPE_VARIABLE, message)),
+                        }
+                    }
+                    _ => INVALID,
+                }
+            }
+        },
+        block_size: block_size,
+        verbosity: verbosity,
+        progress: matches.opt_present("p"),
+        allow_unsafe_paths: matches.opt_present("allow-unsafe-paths"),
+        threads: threads,
+        recompress: recompress,
+        level: level,
+        strip_components: strip_components,
+        include: patterns("include"),
+        exclude: patterns("exclude"),
+        transforms: matches.opt_strs("transform")
+            .iter()
+            .map(|value| {
+                match Transform::new(value) {
+                    Ok(transform) => transform,
+                    Err(e) => error(&format!("Invalid transform {}: {}", value, e)),
+                }
+            })
+            .collect(),
+        dedup_hardlinks: matches.opt_present("dedup-hardlinks"),
+        mmap: matches.opt_present("mmap"),
+        keep_comments: matches.opt_present("keep-comments"),
+        decode_layers: decode_layers,
+        resume: matches.opt_present("resume"),
+        preserve_mtime: matches.opt_present("preserve-mtime"),
+        use_gzip_name: matches.opt_present("use-gzip-name"),
+        manifest: manifest,
+        manifest_json: manifest_json,
+        checksum: checksum,
+        recurse: matches.opt_present("recurse"),
+        max_size: max_size,
+        head: head,
+        ratio_limit: ratio_limit,
+        tar_format: tar_format,
+        encoding: encoding,
+        password: password,
+        skip_errors: matches.opt_present("skip-errors"),
+        owner: id("owner"),
+        group: id("group"),
+        numeric_owner: matches.opt_present("numeric-owner"),
+        file_mode: mode("mode"),
+        dir_mode: mode("dir-mode"),
+        verify_crc: matches.opt_present("verify-crc"),
+        extract: matches.opt_present("x") || extract_to.is_some(),
+        member: matches.opt_str("member"),
+        force: matches.opt_present("f"),
+        dereference: !matches.opt_present("no-dereference"),
+    };
+
+    if matches.opt_present("dereference") && matches.opt_present("no-dereference") {
+        error("--dereference and --no-dereference can't be combined");
+    }
+
+    if detect_only {
+        let src = &free[0];
+
+        if let Err(failure) = check_source(src, settings.dereference) {
+            exit_failure(failure);
+        }
+
+        if let Err(e) = detect(src, settings.explicit_type, settings.verbosity > 0, json) {
+            exit_with(e);
+        }
+
+        return;
+    }
+
+    if list {
+        let src = &free[0];
+
+        if let Err(failure) = check_source(src, settings.dereference) {
+            exit_failure(failure);
+        }
+
+        if let Err(e) = list_file(src,
+                                    settings.explicit_type,
+                                    &stream_options(src, &settings),
+                                    json,
+                                    sizes) {
+            exit_with(e);
+        }
+
+        return;
+    }
+
+    if verify {
+        let src = &free[0];
+        let mut options = stream_options(src, &settings);
+
+        // Nothing is written so there's no point in recompressing
+        options.recompress = INVALID;
+
+        if let Err(failure) = check_source(src, settings.dereference) {
+            exit_failure(failure);
+        }
+
+        if let Err(e) = verify_only(src, settings.explicit_type, &options) {
+            exit_with(e);
+        }
+
+        return;
+    }
+
+    if dry {
+        let src = &free[0];
+        let dst = free.get(1).map(|dst| dst.as_str());
+
+        if let Err(failure) = check_source(src, settings.dereference) {
+            exit_failure(failure);
+        }
+
+        if let Err(e) = dry_run(src, dst, settings.explicit_type, &stream_options(src, &settings)) {
+            exit_with(e);
+        }
+
+        return;
+    }
+
+    if settings.extract && settings.member.is_some() {
+        error("--member and --extract can't be combined");
+    }
+
+    if recompress != INVALID && (settings.extract || settings.member.is_some()) {
+        error("--to only applies when writing a Tar archive");
+    }
+
+    if settings.checksum.is_some() && (settings.extract || settings.member.is_some()) {
+        error("--checksum only applies when writing a Tar archive or decompressed stream");
+    }
+
+    if settings.manifest.is_some() && (settings.extract || settings.member.is_some()) {
+        error("--manifest only applies when converting into a Tar archive");
+    }
+
+    if (settings.owner.is_some() || settings.group.is_some() || settings.numeric_owner) &&
+       (settings.extract || settings.member.is_some()) {
+        error("--owner, --group and --numeric-owner only apply when writing a Tar archive");
+    }
+
+    if (settings.file_mode.is_some() || settings.dir_mode.is_some()) &&
+       (settings.extract || settings.member.is_some()) {
+        error("--mode and --dir-mode only apply when writing a Tar archive");
+    }
+
+    if settings.head.is_some() &&
+       (settings.extract || settings.member.is_some() || settings.resume) {
+        error("--bytes only applies when writing a decompressed stream");
+    }
+
+    if settings.skip_errors && (settings.extract || settings.member.is_some()) {
+        error("--skip-errors only applies when converting into a Tar archive");
+    }
+
+    if append &&
+       (settings.extract || settings.member.is_some() || settings.resume ||
+        settings.head.is_some() || settings.preserve_mtime || settings.use_gzip_name) {
+        error("--append can't be combined with --extract, --member, --resume, --bytes, \
+               --preserve-mtime or --use-gzip-name");
+    }
+
+    if settings.manifest.is_some() && !append && free.len() > 2 {
+        error("--manifest takes a single SRC DST pair");
+    }
+
+    if settings.force && matches.opt_present("no-clobber") {
+        error("--force and --no-clobber can't be combined");
+    }
+
+    handle_interrupts();
+
+    if append {
+        let (dst, srcs) = free.split_last().unwrap();
+
+        match append_into(srcs, dst, collision, &settings) {
+            Ok(()) => {}
+            Err(Failure::Usage(message)) => error(&message),
+            Err(Failure::Stream(e)) => exit_with(e),
+            Err(Failure::Incomplete(_)) => std::process::exit(COMPLETED_WITH_ERRORS),
+        }
+
+        return;
+    }
+
+    // A single pair fails the way it always did, batches carry on past
+    // failures and exit with the code of the first one
+    if free.len() == 2 {
+        match convert(&free[0], &free[1], &settings) {
+            Ok(()) => {}
+            Err(Failure::Usage(message)) => error(&message),
+            Err(Failure::Stream(e)) => exit_with(e),
+            Err(Failure::Incomplete(_)) => std::process::exit(COMPLETED_WITH_ERRORS),
+        }
+
+        return;
+    }
+
+    let mut first_failure = None;
+    let mut failed = 0;
+
+    for pair in free.chunks(2) {
+        if let Err(failure) = convert(&pair[0], &pair[1], &settings) {
+            eprintln!("{} -> {}: {}", pair[0], pair[1], failure.message());
+            failed += 1;
+
+            if first_failure.is_none() {
+                first_failure = Some(failure.exit_code());
+            }
+        }
+    }
 
+    let pairs = free.len() / 2;
+    eprintln!("{} of {} conversions succeeded, {} failed",
+              pairs - failed,
+              pairs,
+              failed);
 
-        stream_file_into(src, dst, explicit_type, block_size, verbose);
+    if let Some(code) = first_failure {
+        std::process::exit(code);
     }
 }
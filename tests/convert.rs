@@ -0,0 +1,632 @@
+// Conversions of the archives in tests/fixtures, compared byte for byte with
+// the golden Tar archives next to them. The hello archives hold the same
+// three members, made with Python's tarfile, zipfile and gzip, bzip2 and xz
+// at their defaults, hello.tar.lz is Python's lzma data in a hand made lzip
+// member. hello.tar.lzo is laid out like lzop writes it, in LZO1X-1 blocks of
+// 4KiB rather than 256KiB so that there are several, hello.tar.blocks.xz is
+// xz -T1 --block-size=4KiB output for the same reason. secret.zip holds them
+// too, encrypted by Info-ZIP's zip -P secret. windows.zip has a directory
+// and a file with no Unix mode, as Windows tools write them, nested.zip a
+// file three directories down. A FIXTURE.tar
+// golden file is what tar-streamer made of FIXTURE, checked with GNU tar
+extern crate flate2;
+extern crate tar;
+extern crate tar_streamer;
+extern crate zip;
+
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::mpsc;
+use std::thread;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use tar_streamer::{ArchiveType, Collision, Manifest, SkippedEntries, StreamError, StreamOptions,
+                   INVALID, GZIP, BZIP2, XZ, LZIP, LZOP, ZIP, CPIO, TAR, BROTLI};
+
+fn fixture(name: &str) -> Vec<u8> {
+    let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+    let mut data = Vec::new();
+
+    File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+    data
+}
+
+// Convert data in memory the way standard input is converted
+fn convert(data: &[u8], options: &StreamOptions) -> Result<Vec<u8>, StreamError> {
+    let mut output = Vec::new();
+
+    try!(tar_streamer::stream_with_options(data, &mut output, INVALID, options));
+    Ok(output)
+}
+
+fn assert_converts_to(name: &str, golden: &str) {
+    let output = convert(&fixture(name), &StreamOptions::default()).unwrap();
+
+    assert!(output == fixture(golden), "{} doesn't convert into {}", name, golden);
+}
+
+#[test]
+fn compressed_tars_decompress_into_the_tar() {
+    assert_converts_to("hello.tar.gz", "hello.tar");
+    assert_converts_to("hello.tar.bz2", "hello.tar");
+    assert_converts_to("hello.tar.xz", "hello.tar");
+    assert_converts_to("hello.tar.lz", "hello.tar");
+    assert_converts_to("hello.tar.lzo", "hello.tar");
+}
+
+// Without the parallel-xz feature the blocks are decompressed on one thread
+#[test]
+fn xz_blocks_decompress_in_threads() {
+    let path = format!("{}/tests/fixtures/hello.tar.blocks.xz", env!("CARGO_MANIFEST_DIR"));
+    let mut output = Vec::new();
+
+    tar_streamer::stream_file(&File::open(&path).unwrap(),
+                              &mut output,
+                              XZ | TAR,
+                              &StreamOptions { threads: 2, ..Default::default() })
+        .unwrap();
+
+    assert!(output == fixture("hello.tar"));
+}
+
+#[test]
+fn concatenated_lzip_members_decompress_into_one_stream() {
+    let mut data = fixture("hello.tar.lz");
+    data.extend(fixture("hello.tar.lz"));
+
+    let mut twice = fixture("hello.tar");
+    twice.extend(fixture("hello.tar"));
+
+    assert!(convert(&data, &StreamOptions::default()).unwrap() == twice);
+}
+
+#[test]
+fn concatenated_gzip_members_decompress_into_one_stream() {
+    let mut data = fixture("hello.tar.gz");
+    data.extend(fixture("hello.tar.gz"));
+
+    let mut twice = fixture("hello.tar");
+    twice.extend(fixture("hello.tar"));
+
+    assert!(convert(&data, &StreamOptions::default()).unwrap() == twice);
+}
+
+#[test]
+fn concatenated_bzip2_streams_decompress_into_one_stream() {
+    let mut data = fixture("hello.tar.bz2");
+    data.extend(fixture("hello.tar.bz2"));
+
+    let mut twice = fixture("hello.tar");
+    twice.extend(fixture("hello.tar"));
+
+    assert!(convert(&data, &StreamOptions::default()).unwrap() == twice);
+}
+
+#[test]
+fn make_decoder_decodes_just_the_compression() {
+    let data = fixture("hello.tar.bz2");
+    let mut decoded = Vec::new();
+
+    tar_streamer::make_decoder(&data[..], BZIP2, false).unwrap().read_to_end(&mut decoded).unwrap();
+    assert!(decoded == fixture("hello.tar"));
+
+    let refused = tar_streamer::make_decoder(&data[..], ZIP, false).map(|_| ());
+    match refused {
+        Err(StreamError::UnsupportedType(ZIP)) => (),
+        _ => panic!("expected UnsupportedType"),
+    }
+}
+
+#[test]
+fn head_stops_after_the_first_bytes() {
+    let options = StreamOptions { head: Some(100), ..Default::default() };
+
+    assert!(convert(&fixture("hello.tar.gz"), &options).unwrap() == &fixture("hello.tar")[..100]);
+
+    match convert(&fixture("hello.zip"), &options) {
+        Err(StreamError::UnsupportedType(ZIP)) => (),
+        other => panic!("expected UnsupportedType, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn nested_compression_decodes_up_to_the_layers_asked_for() {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&fixture("hello.tar.gz")).unwrap();
+    let twice = encoder.finish().unwrap();
+
+    let once = convert(&twice, &StreamOptions::default()).unwrap();
+    let both = convert(&twice, &StreamOptions { decode_layers: 2, ..Default::default() }).unwrap();
+
+    assert!(once == fixture("hello.tar.gz"));
+    assert!(both == fixture("hello.tar"));
+}
+
+#[test]
+fn plain_tar_passes_through() {
+    assert_converts_to("hello.tar", "hello.tar");
+}
+
+#[test]
+fn zip_converts_to_golden_tar() {
+    assert_converts_to("hello.zip", "hello.zip.tar");
+}
+
+#[test]
+fn zip_converts_the_same_with_threads() {
+    let options = StreamOptions { threads: 2, ..Default::default() };
+
+    assert!(convert(&fixture("hello.zip"), &options).unwrap() == fixture("hello.zip.tar"));
+}
+
+// Write end of an in-memory pipe, there's no seeking back in what's been sent
+struct Pipe(mpsc::Sender<Vec<u8>>);
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.send(buf.to_vec()).unwrap();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn zip_members_without_unix_modes_get_the_defaults() {
+    let output = convert(&fixture("windows.zip"), &StreamOptions::default()).unwrap();
+    let mut archive = tar::Archive::new(Cursor::new(output));
+    let modes = archive.entries()
+        .unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            (entry.path().unwrap().to_str().unwrap().to_owned(), entry.header().mode().unwrap())
+        })
+        .collect::<Vec<(String, u32)>>();
+
+    assert_eq!(modes,
+               vec![(String::from("docs/"), 0o755), (String::from("docs/readme.txt"), 0o644)]);
+}
+
+#[test]
+fn nested_zip_directories_become_tar_directories() {
+    let output = convert(&fixture("nested.zip"), &StreamOptions::default()).unwrap();
+    let mut archive = tar::Archive::new(Cursor::new(output));
+    let members = archive.entries()
+        .unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            let header = entry.header();
+            (entry.path().unwrap().to_str().unwrap().to_owned(),
+             header.entry_type(),
+             header.size().unwrap())
+        })
+        .collect::<Vec<(String, tar::EntryType, u64)>>();
+
+    assert_eq!(members,
+               vec![(String::from("a/"), tar::EntryType::Directory, 0),
+                    (String::from("a/b/"), tar::EntryType::Directory, 0),
+                    (String::from("a/b/c/"), tar::EntryType::Directory, 0),
+                    (String::from("a/b/c/deep.txt"), tar::EntryType::Regular, 11)]);
+}
+
+#[test]
+fn zip64_archives_with_many_members_convert() {
+    // Past 65535 entries the count only fits the zip64 end of central directory
+    let count = 0x10000 + 1;
+    let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let stored = zip::write::FileOptions::default()
+        .compression_method(zip::CompressionMethod::Stored);
+
+    for i in 0..count {
+        writer.start_file(format!("{}.txt", i), stored).unwrap();
+    }
+    writer.write_all(b"last").unwrap();
+    let data = writer.finish().unwrap().into_inner();
+    assert!(data.windows(4).any(|signature| signature == b"PK\x06\x06"));
+
+    let output = convert(&data, &StreamOptions::default()).unwrap();
+    let mut archive = tar::Archive::new(Cursor::new(output));
+    let members = archive.entries()
+        .unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            (entry.path().unwrap().to_str().unwrap().to_owned(), entry.header().size().unwrap())
+        })
+        .collect::<Vec<(String, u64)>>();
+
+    assert_eq!(members.len(), count);
+    assert_eq!(members[count - 1], (String::from("65536.txt"), 4));
+}
+
+#[test]
+fn zip_file_converts_into_a_pipe() {
+    let (sender, receiver) = mpsc::channel();
+    let path = format!("{}/tests/fixtures/hello.zip", env!("CARGO_MANIFEST_DIR"));
+    let reader = thread::spawn(move || receiver.iter().collect::<Vec<Vec<u8>>>().concat());
+
+    tar_streamer::stream_file(&File::open(&path).unwrap(),
+                              Pipe(sender),
+                              ZIP,
+                              &StreamOptions::default())
+        .unwrap();
+
+    // Ends with the two zero blocks of the finished archive
+    let output = reader.join().unwrap();
+    assert!(output[output.len() - 1024..].iter().all(|&b| b == 0));
+    assert!(output == fixture("hello.zip.tar"));
+}
+
+// Writer counting the bytes it's given and keeping none of them
+struct Counter(u64);
+
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Peak resident set size of the process in KiB, as Linux reports it
+fn peak_rss_kib() -> u64 {
+    let mut status = String::new();
+    File::open("/proc/self/status").unwrap().read_to_string(&mut status).unwrap();
+
+    let line = status.lines().find(|line| line.starts_with("VmHWM:")).unwrap();
+    line.split_whitespace().nth(1).unwrap().parse().unwrap()
+}
+
+// Writes and reads 2 GiB, run with cargo test --release -- --ignored
+#[test]
+#[ignore]
+fn huge_zip_member_converts_with_threads_in_bounded_memory() {
+    let size = 2u64 << 30;
+    let path = std::env::temp_dir().join(format!("tar-streamer-huge-{}.zip", std::process::id()));
+    let mut writer = zip::ZipWriter::new(File::create(&path).unwrap());
+
+    writer.start_file("huge.bin", zip::write::FileOptions::default()).unwrap();
+    for _ in 0..size >> 20 {
+        writer.write_all(&[0u8; 1 << 20]).unwrap();
+    }
+    writer.finish().unwrap();
+
+    let mut output = Counter(0);
+    let options = StreamOptions { threads: 2, ..Default::default() };
+    let result = tar_streamer::stream_file(&File::open(&path).unwrap(), &mut output, ZIP, &options);
+    std::fs::remove_file(&path).unwrap();
+    result.unwrap();
+
+    // The member's data plus its header, the end of archive and padding
+    assert!(output.0 > size && output.0 < size + 64 * 1024);
+    assert!(peak_rss_kib() < 256 * 1024, "peak RSS of {} KiB", peak_rss_kib());
+}
+
+#[test]
+fn encrypted_zip_member_is_refused_by_name() {
+    let mut data = fixture("hello.zip");
+
+    // Set the encryption bit in the flags of every local and central header
+    for i in 0..data.len() - 4 {
+        match &data[i..i + 4] {
+            b"PK\x03\x04" => data[i + 6] |= 1,
+            b"PK\x01\x02" => data[i + 8] |= 1,
+            _ => (),
+        }
+    }
+
+    match convert(&data, &StreamOptions::default()) {
+        Err(StreamError::Encrypted(ref name)) if name == "dir/" => (),
+        other => panic!("expected Encrypted, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn encrypted_zip_members_decrypt_with_the_password() {
+    let password = |password: &[u8]| {
+        StreamOptions { password: Some(password.to_vec()), ..Default::default() }
+    };
+
+    let output = convert(&fixture("secret.zip"), &password(b"secret")).unwrap();
+    let mut archive = tar::Archive::new(Cursor::new(output));
+    let mut hello = archive.entries()
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .find(|entry| entry.path().unwrap().to_str() == Some("dir/hello.txt"))
+        .unwrap();
+    let mut data = String::new();
+
+    hello.read_to_string(&mut data).unwrap();
+    assert_eq!(data, "Hello world\n");
+
+    match convert(&fixture("secret.zip"), &password(b"wrong")) {
+        Err(StreamError::BadPassword(_)) => (),
+        other => panic!("expected BadPassword, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn corrupt_zip_member_is_skipped_with_skip_errors() {
+    let mut data = fixture("hello.zip");
+    let name = data.windows(13).position(|window| window == b"dir/hello.txt").unwrap();
+    let extra = data[name - 2] as usize | (data[name - 1] as usize) << 8;
+
+    // The first byte of the member's deflate data
+    data[name + 13 + extra] ^= 0xff;
+
+    let options = StreamOptions { skip_errors: Some(SkippedEntries::new()), ..Default::default() };
+    let output = convert(&data, &options).unwrap();
+    let mut archive = tar::Archive::new(Cursor::new(output));
+    let paths: Vec<String> = archive.entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().path().unwrap().to_string_lossy().into_owned())
+        .collect();
+    let skipped = options.skip_errors.unwrap().entries();
+
+    assert_eq!(paths, vec!["dir/", "dir/empty"]);
+    assert_eq!(skipped.len(), 1);
+    assert_eq!(skipped[0].name, "dir/hello.txt");
+}
+
+#[test]
+fn verify_crc_names_the_corrupt_zip_member() {
+    let mut data = fixture("hello.zip");
+    let contents = data.windows(11).position(|window| window == b"hello world").unwrap();
+
+    // hello.zip stores its members, this is the data of dir/hello.txt
+    data[contents] = b'j';
+
+    let options = StreamOptions { verify_crc: true, ..Default::default() };
+
+    match convert(&data, &options) {
+        Err(StreamError::Corrupt(ref reason)) => {
+            assert_eq!(reason,
+                       "CRC-32 of 'dir/hello.txt' is 4333a5b2, the archive records af083b2d")
+        }
+        other => panic!("expected Corrupt, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn cpio_converts_to_golden_tar() {
+    assert_converts_to("hello.cpio", "hello.cpio.tar");
+}
+
+#[test]
+fn zip_symlink_becomes_tar_symlink() {
+    assert_converts_to("link.zip", "link.zip.tar");
+
+    let output = convert(&fixture("link.zip"), &StreamOptions::default()).unwrap();
+    let mut archive = tar::Archive::new(Cursor::new(output));
+    let link = archive.entries()
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .find(|entry| entry.path().unwrap().to_str() == Some("link"))
+        .unwrap();
+
+    assert_eq!(link.header().entry_type(), tar::EntryType::Symlink);
+    assert_eq!(link.link_name().unwrap().unwrap().to_str(), Some("target.txt"));
+}
+
+#[test]
+fn zip_symlink_round_trips_through_tar() {
+    let root = std::env::temp_dir().join(format!("tar-streamer-link-{}", std::process::id()));
+    let options = StreamOptions { threads: 2, ..Default::default() };
+    let output = convert(&fixture("link.zip"), &options).unwrap();
+
+    // Unpacked again the link points at the member it did in the zip
+    tar_streamer::extract(&output[..], &root, TAR, &StreamOptions::default()).unwrap();
+    let target = std::fs::read_link(root.join("link")).unwrap();
+    let mut content = String::new();
+    File::open(root.join("link")).unwrap().read_to_string(&mut content).unwrap();
+    std::fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(target.to_str(), Some("target.txt"));
+    assert!(output == fixture("link.zip.tar"));
+    assert_eq!(content, "pointed at\n");
+}
+
+#[test]
+fn truncated_tar_is_refused() {
+    let data = fixture("hello.tar");
+
+    match convert(&data[..1000], &StreamOptions::default()) {
+        Err(StreamError::Truncated) => (),
+        other => panic!("expected Truncated, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn empty_input_is_refused() {
+    match convert(&[], &StreamOptions::default()) {
+        Err(StreamError::Empty) => (),
+        other => panic!("expected Empty, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn magic_detects_the_fixtures() {
+    let expected: [(&str, ArchiveType); 8] = [("hello.tar", TAR),
+                                              ("hello.tar.gz", GZIP),
+                                              ("hello.tar.bz2", BZIP2),
+                                              ("hello.tar.xz", XZ),
+                                              ("hello.tar.lz", LZIP),
+                                              ("hello.tar.lzo", LZOP),
+                                              ("hello.zip", ZIP),
+                                              ("hello.cpio", CPIO)];
+
+    for &(name, typ) in expected.iter() {
+        let (detected, _) = tar_streamer::peek_archive_type(&fixture(name)[..]).unwrap();
+
+        assert_eq!(detected, typ, "{}", name);
+    }
+}
+
+#[test]
+fn names_decide_when_file_cannot_run() {
+    let detect = |suffix: &str| {
+        let name = format!("tar-streamer-{}{}", std::process::id(), suffix);
+        let path = std::env::temp_dir().join(name);
+        File::create(&path).unwrap().write_all(b"no magic here").unwrap();
+
+        let detected = tar_streamer::get_archive_type_with(path.to_str().unwrap(),
+                                                           "/nonexistent/file",
+                                                           false);
+        std::fs::remove_file(&path).unwrap();
+        detected.unwrap()
+    };
+
+    // Brotli streams have no magic bytes, so detection has to go further
+    assert_eq!(detect(".tar.br"), TAR | BROTLI);
+    // Content that doesn't sniff as gzip is left to the name as well
+    assert_eq!(detect(".tar.gz"), TAR | GZIP);
+}
+
+#[test]
+fn manifest_records_the_converted_members() {
+    let options = StreamOptions { manifest: Some(Manifest::new(Some("md5"))), ..Default::default() };
+
+    assert!(convert(&fixture("hello.zip"), &options).unwrap() == fixture("hello.zip.tar"));
+
+    let entries = options.manifest.unwrap().entries();
+    let hello = entries.iter().find(|entry| entry.name == "dir/hello.txt").unwrap();
+
+    assert_eq!(entries.len(), 3);
+    assert_eq!((hello.size, hello.mode), (12, 0o644));
+    assert_eq!(hello.digest, Some(String::from("6f5902ac237024bdd0c176cb93063dc4")));
+}
+
+#[test]
+fn owner_and_group_override_the_members() {
+    let options = StreamOptions {
+        owner: Some(0),
+        group: Some(0),
+        numeric_owner: true,
+        ..Default::default()
+    };
+
+    for name in ["hello.zip", "hello.tar"].iter() {
+        let output = convert(&fixture(name), &options).unwrap();
+        let mut archive = tar::Archive::new(Cursor::new(output));
+
+        for entry in archive.entries().unwrap() {
+            let header = entry.unwrap().header().clone();
+
+            assert_eq!((header.uid().unwrap(), header.gid().unwrap()), (0, 0), "{}", name);
+            assert_eq!(header.username().unwrap(), Some(""), "{}", name);
+        }
+    }
+}
+
+#[test]
+fn mode_and_dir_mode_override_the_permissions() {
+    let options = StreamOptions {
+        file_mode: Some(0o600),
+        dir_mode: Some(0o700),
+        ..Default::default()
+    };
+    let output = convert(&fixture("hello.zip"), &options).unwrap();
+    let mut archive = tar::Archive::new(Cursor::new(output));
+
+    for entry in archive.entries().unwrap() {
+        let header = entry.unwrap().header().clone();
+        let expected = if header.entry_type().is_dir() { 0o700 } else { 0o600 };
+
+        assert_eq!(header.mode().unwrap() & 0o7777, expected);
+    }
+}
+
+#[test]
+fn append_renames_the_members_of_later_sources() {
+    let zip = File::open(format!("{}/tests/fixtures/hello.zip", env!("CARGO_MANIFEST_DIR")))
+        .unwrap();
+    let tar = File::open(format!("{}/tests/fixtures/hello.tar", env!("CARGO_MANIFEST_DIR")))
+        .unwrap();
+    let options = StreamOptions::default();
+    let sources = [(&zip, ZIP, &options), (&tar, TAR, &options)];
+    let mut output = Vec::new();
+
+    tar_streamer::append_files(&sources, &mut output, Collision::Rename, &options).unwrap();
+
+    let mut archive = tar::Archive::new(Cursor::new(output));
+    let names: Vec<String> = archive.entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().path().unwrap().to_string_lossy().into_owned())
+        .collect();
+
+    assert_eq!(names,
+               vec!["dir/", "dir/hello.txt", "dir/empty", "dir/hello.txt.1", "dir/empty.1"]);
+
+    // The Tar archive is read from where the file offset is
+    (&tar).seek(SeekFrom::Start(0)).unwrap();
+
+    match tar_streamer::append_files(&sources, Vec::new(), Collision::Error, &options) {
+        Err(StreamError::Collision(ref name)) if name == "dir/hello.txt" => (),
+        other => panic!("expected a collision, got {:?}", other.err()),
+    }
+}
+
+// Tar archive of a symlink to outside, followed by a member below the symlink
+fn escaping_tar(outside: &std::path::Path) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_path("top/a").unwrap();
+    // set_link_name() refuses absolute paths, a hostile archive doesn't care
+    let target = outside.to_str().unwrap().as_bytes();
+    header.as_old_mut().linkname[..target.len()].copy_from_slice(target);
+    header.set_size(0);
+    header.set_cksum();
+    builder.append(&header, &[][..]).unwrap();
+
+    let mut header = tar::Header::new_gnu();
+    header.set_path("top/a/x").unwrap();
+    header.set_mode(0o644);
+    header.set_size(4);
+    header.set_cksum();
+    builder.append(&header, &b"evil"[..]).unwrap();
+
+    builder.into_inner().unwrap()
+}
+
+#[test]
+fn extracting_refuses_to_write_through_symlinks() {
+    let root = std::env::temp_dir().join(format!("tar-streamer-escape-{}", std::process::id()));
+    let outside = root.join("outside");
+    std::fs::create_dir_all(&outside).unwrap();
+
+    for &strip in [0, 1].iter() {
+        let out = root.join(format!("out{}", strip));
+        let options = StreamOptions { strip_components: strip, ..Default::default() };
+        let result = tar_streamer::extract(&escaping_tar(&outside)[..], &out, TAR, &options);
+
+        match result {
+            Err(StreamError::UnsafePath(_)) => (),
+            other => panic!("expected UnsafePath, got {:?}", other),
+        }
+        assert!(!outside.join("x").exists());
+    }
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn list_reports_zip_members() {
+    let mut names = Vec::new();
+
+    tar_streamer::list(&fixture("hello.zip")[..],
+                       INVALID,
+                       &StreamOptions::default(),
+                       |entry| names.push((entry.name.clone(), entry.is_dir)))
+        .unwrap();
+
+    assert_eq!(names,
+               vec![(String::from("dir/"), true),
+                    (String::from("dir/hello.txt"), false),
+                    (String::from("dir/empty"), false)]);
+}